@@ -0,0 +1,5 @@
+DROP TRIGGER ledger_entries_balanced ON ledger_entries;
+DROP FUNCTION check_ledger_transfer_balanced;
+DROP TABLE ledger_entries;
+DROP SEQUENCE ledger_entries_seq;
+DROP TABLE idempotency_keys;
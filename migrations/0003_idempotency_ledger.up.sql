@@ -0,0 +1,60 @@
+CREATE TABLE idempotency_keys ( -- mydb.public.idempotency_keys
+    idempotency_key VARCHAR(255) PRIMARY KEY,
+    -- transfer parameters, a key cannot be reused for another transfer
+    request_fingerprint VARCHAR(255) NOT NULL,
+    transaction_id BIGINT REFERENCES transactions (id),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE SEQUENCE ledger_entries_seq START WITH 1000;
+
+CREATE TABLE ledger_entries ( -- mydb.public.ledger_entries
+    id BIGINT PRIMARY KEY DEFAULT nextval('ledger_entries_seq'),
+    account_id BIGINT NOT NULL REFERENCES accounts (id),
+    -- NULL for the opening balance of an account
+    transaction_id BIGINT REFERENCES transactions (id),
+    -- negative for the source account of a transfer
+    amount NUMERIC(10, 2) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ledger_entries_account_id_idx ON ledger_entries (account_id);
+CREATE INDEX ledger_entries_transaction_id_idx ON ledger_entries (transaction_id);
+
+-- existing transfers (skipping the ones of deleted accounts) and opening balances
+INSERT INTO ledger_entries (account_id, transaction_id, amount, created_at)
+SELECT tx.src_account_id, tx.id, -tx.amount, tx.tx_timestamp
+FROM transactions tx
+    JOIN accounts src_acc ON tx.src_account_id = src_acc.id
+    JOIN accounts dst_acc ON tx.dst_account_id = dst_acc.id
+WHERE tx.amount IS NOT NULL
+UNION ALL
+SELECT tx.dst_account_id, tx.id, tx.amount, tx.tx_timestamp
+FROM transactions tx
+    JOIN accounts src_acc ON tx.src_account_id = src_acc.id
+    JOIN accounts dst_acc ON tx.dst_account_id = dst_acc.id
+WHERE tx.amount IS NOT NULL;
+
+INSERT INTO ledger_entries (account_id, amount)
+SELECT acc.id, acc.balance - COALESCE(SUM(l.amount), 0)
+FROM accounts acc
+    LEFT JOIN ledger_entries l ON l.account_id = acc.id
+GROUP BY acc.id, acc.balance;
+
+-- entries of a transfer must sum to zero, checked on commit when all of them are inserted
+CREATE FUNCTION check_ledger_transfer_balanced() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.transaction_id IS NOT NULL AND (
+        SELECT SUM(amount) FROM ledger_entries WHERE transaction_id = NEW.transaction_id
+    ) <> 0 THEN
+        RAISE EXCEPTION 'Ledger entries of transaction % do not sum to zero', NEW.transaction_id
+            USING ERRCODE = 'check_violation', CONSTRAINT = 'ledger_entries_balanced';
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER ledger_entries_balanced
+    AFTER INSERT OR UPDATE ON ledger_entries
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION check_ledger_transfer_balanced();
@@ -0,0 +1,15 @@
+ALTER TABLE transactions DROP COLUMN status;
+DROP TYPE transfer_status;
+
+ALTER TABLE transactions DROP COLUMN currency;
+
+ALTER TABLE transactions DROP CONSTRAINT transactions_amount_check;
+ALTER TABLE transactions ALTER COLUMN amount DROP NOT NULL;
+ALTER TABLE transactions ALTER COLUMN amount SET DEFAULT 0.00;
+
+DROP INDEX transactions_tx_timestamp_idx;
+DROP INDEX transactions_dst_account_id_idx;
+DROP INDEX transactions_src_account_id_idx;
+
+-- the duplicated src foreign key is not restored
+ALTER TABLE transactions DROP CONSTRAINT transactions_dst_account_id_fkey;
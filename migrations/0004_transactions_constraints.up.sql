@@ -0,0 +1,42 @@
+-- legacy rows the constraints below would reject, 0003 left them out of the ledger or
+-- recorded them as moving nothing:
+-- transfers to accounts deleted since, the source balances already paid them
+DELETE FROM idempotency_keys k USING transactions tx
+WHERE k.transaction_id = tx.id
+    AND NOT EXISTS (SELECT 1 FROM accounts acc WHERE acc.id = tx.dst_account_id);
+DELETE FROM transactions tx
+WHERE NOT EXISTS (SELECT 1 FROM accounts acc WHERE acc.id = tx.dst_account_id);
+-- transfers without an amount or of the old 0.00 default, their ledger entries are zero
+DELETE FROM idempotency_keys k USING transactions tx
+WHERE k.transaction_id = tx.id AND (tx.amount IS NULL OR tx.amount = 0);
+DELETE FROM ledger_entries l USING transactions tx
+WHERE l.transaction_id = tx.id AND (tx.amount IS NULL OR tx.amount = 0);
+DELETE FROM transactions WHERE amount IS NULL OR amount = 0;
+-- negative transfers moved the money the other way, the ledger entries stay as they are
+UPDATE transactions
+SET src_account_id = dst_account_id, dst_account_id = src_account_id, amount = -amount
+WHERE amount < 0;
+
+-- 0002 declared the src foreign key twice and left dst_account_id unconstrained
+ALTER TABLE transactions DROP CONSTRAINT IF EXISTS transactions_src_account_id_fkey1;
+ALTER TABLE transactions
+    ADD CONSTRAINT transactions_dst_account_id_fkey
+    FOREIGN KEY (dst_account_id) REFERENCES accounts (id);
+
+CREATE INDEX transactions_src_account_id_idx ON transactions (src_account_id);
+CREATE INDEX transactions_dst_account_id_idx ON transactions (dst_account_id);
+CREATE INDEX transactions_tx_timestamp_idx ON transactions (tx_timestamp);
+
+-- a transfer always moves money, the old 0.00 default contradicts that
+ALTER TABLE transactions ALTER COLUMN amount DROP DEFAULT;
+ALTER TABLE transactions ALTER COLUMN amount SET NOT NULL;
+ALTER TABLE transactions ADD CONSTRAINT transactions_amount_check CHECK (amount > 0);
+
+-- ISO 4217 code, all the existing transfers were made in dollars
+ALTER TABLE transactions
+    ADD COLUMN currency CHAR(3) NOT NULL DEFAULT 'USD'
+    CONSTRAINT transactions_currency_check CHECK (currency ~ '^[A-Z]{3}$');
+
+CREATE TYPE transfer_status AS ENUM ('pending', 'completed', 'failed', 'reversed');
+
+ALTER TABLE transactions ADD COLUMN status transfer_status NOT NULL DEFAULT 'completed';
@@ -0,0 +1,2 @@
+DROP TABLE products;
+DROP SEQUENCE products_seq;
@@ -0,0 +1,8 @@
+CREATE SEQUENCE products_seq START WITH 1000;
+
+CREATE TABLE products ( -- mydb.public.products
+    id BIGINT PRIMARY KEY DEFAULT nextval('products_seq'),
+    name VARCHAR(255) NOT NULL UNIQUE,
+    -- units not reserved yet
+    stock BIGINT NOT NULL DEFAULT 0 CHECK (stock >= 0)
+);
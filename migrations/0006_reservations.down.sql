@@ -0,0 +1,2 @@
+DROP TABLE reservations;
+DROP SEQUENCE reservations_seq;
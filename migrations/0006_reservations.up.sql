@@ -0,0 +1,10 @@
+CREATE SEQUENCE reservations_seq START WITH 1000;
+
+CREATE TABLE reservations ( -- mydb.public.reservations
+    id BIGINT PRIMARY KEY DEFAULT nextval('reservations_seq'),
+    product_id BIGINT NOT NULL REFERENCES products (id),
+    quantity BIGINT NOT NULL CHECK (quantity > 0),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX reservations_product_id_idx ON reservations (product_id);
@@ -0,0 +1,2 @@
+DROP TABLE shipments;
+DROP SEQUENCE shipments_seq;
@@ -0,0 +1,9 @@
+CREATE SEQUENCE shipments_seq START WITH 1000;
+
+CREATE TABLE shipments ( -- mydb.public.shipments
+    id BIGINT PRIMARY KEY DEFAULT nextval('shipments_seq'),
+    -- one shipment per reservation
+    reservation_id BIGINT NOT NULL UNIQUE REFERENCES reservations (id),
+    address TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
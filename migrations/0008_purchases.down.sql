@@ -0,0 +1,2 @@
+DROP TABLE purchases;
+DROP SEQUENCE purchases_seq;
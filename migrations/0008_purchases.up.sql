@@ -0,0 +1,8 @@
+CREATE SEQUENCE purchases_seq START WITH 1000;
+
+CREATE TABLE purchases ( -- mydb.public.purchases
+    id BIGINT PRIMARY KEY DEFAULT nextval('purchases_seq'),
+    reservation_id BIGINT NOT NULL UNIQUE REFERENCES reservations (id),
+    shipment_id BIGINT NOT NULL UNIQUE REFERENCES shipments (id),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
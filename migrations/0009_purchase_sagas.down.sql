@@ -0,0 +1,5 @@
+DROP TABLE purchase_saga_steps;
+DROP SEQUENCE purchase_saga_steps_seq;
+DROP SEQUENCE purchase_sagas_seq;
+DROP TYPE purchase_saga_step;
+ALTER TABLE reservations DROP COLUMN released_at;
@@ -0,0 +1,27 @@
+-- set when the stock of a reservation is given back
+ALTER TABLE reservations ADD COLUMN released_at TIMESTAMP;
+
+CREATE TYPE purchase_saga_step AS ENUM (
+    'started', 'reserved', 'shipped', 'completed', 'compensating', 'compensated', 'aborted'
+);
+
+CREATE SEQUENCE purchase_sagas_seq START WITH 1000;
+CREATE SEQUENCE purchase_saga_steps_seq START WITH 1000;
+
+CREATE TABLE purchase_saga_steps ( -- mydb.public.purchase_saga_steps, append-only
+    id BIGINT PRIMARY KEY DEFAULT nextval('purchase_saga_steps_seq'),
+    saga_id BIGINT NOT NULL,
+    step purchase_saga_step NOT NULL,
+    -- 'started'
+    product_id BIGINT,
+    quantity BIGINT,
+    address TEXT,
+    -- 'reserved'
+    reservation_id BIGINT REFERENCES reservations (id),
+    -- 'shipped'
+    shipment_id BIGINT REFERENCES shipments (id),
+    -- 'compensating' and 'aborted'
+    error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    UNIQUE (saga_id, step)
+);
@@ -0,0 +1,4 @@
+DROP INDEX reservations_held_expires_at_idx;
+ALTER TABLE reservations DROP COLUMN expires_at;
+ALTER TABLE reservations DROP COLUMN status;
+DROP TYPE reservation_status;
@@ -0,0 +1,15 @@
+CREATE TYPE reservation_status AS ENUM ('held', 'confirmed', 'released', 'expired');
+
+-- a held reservation not confirmed by expires_at is given back to the stock
+ALTER TABLE reservations
+    ADD COLUMN status reservation_status NOT NULL DEFAULT 'held',
+    ADD COLUMN expires_at TIMESTAMP;
+
+-- the reservations before the statuses were either released or bought
+UPDATE reservations
+SET status = CASE WHEN released_at IS NULL THEN 'confirmed' ELSE 'released' END::reservation_status,
+    expires_at = created_at;
+
+ALTER TABLE reservations ALTER COLUMN expires_at SET NOT NULL;
+
+CREATE INDEX reservations_held_expires_at_idx ON reservations (expires_at) WHERE status = 'held';
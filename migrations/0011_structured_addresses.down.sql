@@ -0,0 +1,6 @@
+ALTER TABLE purchase_saga_steps DROP COLUMN city, DROP COLUMN postal_code, DROP COLUMN country;
+ALTER TABLE purchase_saga_steps RENAME COLUMN street TO address;
+
+UPDATE shipments SET street = concat_ws(', ', street, NULLIF(postal_code || ' ' || city, ' '), NULLIF(country, ''));
+ALTER TABLE shipments DROP COLUMN city, DROP COLUMN postal_code, DROP COLUMN country;
+ALTER TABLE shipments RENAME COLUMN street TO address;
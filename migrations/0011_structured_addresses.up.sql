@@ -0,0 +1,23 @@
+-- the address becomes street, city, postal code and country; the old free-form
+-- addresses are kept as streets
+ALTER TABLE shipments RENAME COLUMN address TO street;
+ALTER TABLE shipments
+    ADD COLUMN city TEXT NOT NULL DEFAULT '',
+    ADD COLUMN postal_code TEXT NOT NULL DEFAULT '',
+    ADD COLUMN country TEXT NOT NULL DEFAULT '';
+ALTER TABLE shipments
+    ALTER COLUMN city DROP DEFAULT,
+    ALTER COLUMN postal_code DROP DEFAULT,
+    ALTER COLUMN country DROP DEFAULT;
+
+-- 'started'
+ALTER TABLE purchase_saga_steps RENAME COLUMN address TO street;
+ALTER TABLE purchase_saga_steps
+    ADD COLUMN city TEXT,
+    ADD COLUMN postal_code TEXT,
+    ADD COLUMN country TEXT;
+-- like the shipments, so that the unfinished sagas can be recovered; their shipping then
+-- fails on the incomplete address and the purchase is compensated
+UPDATE purchase_saga_steps
+SET city = '', postal_code = '', country = ''
+WHERE step = 'started';
@@ -0,0 +1,18 @@
+DROP TABLE order_lines;
+DROP SEQUENCE order_lines_seq;
+DROP TABLE orders;
+DROP SEQUENCE orders_seq;
+DROP TYPE order_fulfillment;
+
+-- shipments of several reservations keep the first one
+ALTER TABLE shipments ADD COLUMN reservation_id BIGINT UNIQUE REFERENCES reservations (id);
+UPDATE shipments s SET reservation_id = (SELECT MIN(r.id) FROM reservations r WHERE r.shipment_id = s.id);
+ALTER TABLE shipments ALTER COLUMN reservation_id SET NOT NULL;
+DROP INDEX reservations_shipment_id_idx;
+ALTER TABLE reservations DROP COLUMN shipment_id;
+ALTER TABLE shipments DROP COLUMN warehouse_id;
+
+ALTER TABLE reservations DROP COLUMN warehouse_id;
+ALTER TABLE products DROP COLUMN warehouse_id;
+DROP TABLE warehouses;
+DROP SEQUENCE warehouses_seq;
@@ -0,0 +1,54 @@
+CREATE SEQUENCE warehouses_seq START WITH 1000;
+
+CREATE TABLE warehouses ( -- mydb.public.warehouses
+    id BIGINT PRIMARY KEY DEFAULT nextval('warehouses_seq'),
+    name VARCHAR(255) NOT NULL UNIQUE
+);
+
+-- ID 1000, the stock so far was in one place
+INSERT INTO warehouses (name) VALUES ('main');
+
+ALTER TABLE products ADD COLUMN warehouse_id BIGINT NOT NULL DEFAULT 1000 REFERENCES warehouses (id);
+
+-- the warehouse the stock of a reservation is held in
+ALTER TABLE reservations ADD COLUMN warehouse_id BIGINT REFERENCES warehouses (id);
+UPDATE reservations r SET warehouse_id = p.warehouse_id FROM products p WHERE p.id = r.product_id;
+ALTER TABLE reservations ALTER COLUMN warehouse_id SET NOT NULL;
+
+-- a shipment leaves one warehouse with one or more reservations
+ALTER TABLE shipments ADD COLUMN warehouse_id BIGINT REFERENCES warehouses (id);
+ALTER TABLE reservations ADD COLUMN shipment_id BIGINT REFERENCES shipments (id);
+UPDATE reservations r SET shipment_id = s.id FROM shipments s WHERE s.reservation_id = r.id;
+UPDATE shipments s SET warehouse_id = r.warehouse_id FROM reservations r WHERE r.id = s.reservation_id;
+ALTER TABLE shipments ALTER COLUMN warehouse_id SET NOT NULL;
+ALTER TABLE shipments DROP COLUMN reservation_id;
+CREATE INDEX reservations_shipment_id_idx ON reservations (shipment_id);
+
+CREATE TYPE order_fulfillment AS ENUM ('all_or_nothing', 'partial');
+
+CREATE SEQUENCE orders_seq START WITH 1000;
+
+CREATE TABLE orders ( -- mydb.public.orders
+    id BIGINT PRIMARY KEY DEFAULT nextval('orders_seq'),
+    fulfillment order_fulfillment NOT NULL,
+    street TEXT NOT NULL,
+    city TEXT NOT NULL,
+    postal_code TEXT NOT NULL,
+    country TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE SEQUENCE order_lines_seq START WITH 1000;
+
+CREATE TABLE order_lines ( -- mydb.public.order_lines
+    id BIGINT PRIMARY KEY DEFAULT nextval('order_lines_seq'),
+    order_id BIGINT NOT NULL REFERENCES orders (id),
+    product_id BIGINT NOT NULL REFERENCES products (id),
+    ordered BIGINT NOT NULL CHECK (ordered > 0),
+    -- NULL when nothing was in stock
+    reservation_id BIGINT UNIQUE REFERENCES reservations (id),
+    -- left to be fulfilled later, only with the 'partial' fulfillment
+    backordered BIGINT NOT NULL CHECK (backordered >= 0)
+);
+
+CREATE INDEX order_lines_order_id_idx ON order_lines (order_id);
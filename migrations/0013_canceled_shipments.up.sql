@@ -0,0 +1,2 @@
+-- set when a shipment is canceled, its reservations are taken off it
+ALTER TABLE shipments ADD COLUMN canceled_at TIMESTAMP;
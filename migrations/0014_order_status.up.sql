@@ -0,0 +1,8 @@
+CREATE TYPE order_status AS ENUM ('placing', 'placed', 'failed');
+
+-- the orders so far were placed in one transaction; the ones placed step by step stay
+-- 'placing' until they're placed, or compensated as 'failed'
+ALTER TABLE orders ADD COLUMN status order_status NOT NULL DEFAULT 'placed';
+ALTER TABLE orders ALTER COLUMN status SET DEFAULT 'placing';
+
+CREATE INDEX orders_placing_idx ON orders (id) WHERE status = 'placing';
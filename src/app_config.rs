@@ -0,0 +1,208 @@
+//! Settings merged from `config/default.toml`, `config/{PROFILE}.toml` and `APP__*` env
+//! variables, e.g. `APP__DB__HOST=qwerty` overrides `db.host`
+
+use std::{path::PathBuf, time::Duration};
+
+use config::{Config, ConfigError, Environment, File};
+use serde::Deserialize;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions, PgSslMode},
+    PgPool,
+};
+
+use crate::secret::Secret;
+
+mod check;
+pub use check::{check, check_section, diff, entries, CheckError, Difference, Entry, Violation};
+
+// profiles that must not keep secrets in the config files
+const PRODUCTION_PROFILES: &[&str] = &["prod"];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppConfig {
+    pub db: DbConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DbConfig {
+    pub host: String,
+    pub port: u16,
+    pub login: String,
+    /// Literal password, only allowed outside of production profiles.
+    /// Holds the resolved password of `password_file` or `password_env` after loading.
+    #[serde(default)]
+    pub password: Option<Secret<String>>,
+    /// File with the password, e.g. a mounted Docker or Kubernetes secret
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// Env variable with the password
+    #[serde(default)]
+    pub password_env: Option<String>,
+    pub database: String,
+    /// Max connections of the pool
+    pub pool_size: u32,
+    /// How long to wait for a free connection of the pool
+    pub acquire_timeout_secs: u64,
+    pub ssl_mode: SslMode,
+}
+
+/// Same as `sslmode` of libpq
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ServerConfig {
+    pub listen_port: u16,
+}
+
+/// `PROFILE` env variable, `dev` when not set
+pub fn profile() -> String {
+    std::env::var("PROFILE").unwrap_or_else(|_| "dev".into())
+}
+
+pub fn is_production(profile: &str) -> bool {
+    PRODUCTION_PROFILES.contains(&profile)
+}
+
+/// All the sources of a profile merged, sections are deserialized by their users
+pub fn load_profile(profile: &str) -> Result<Config, ConfigError> {
+    Config::builder()
+        .add_source(File::with_name("config/default.toml"))
+        .add_source(File::with_name(&format!("config/{profile}.toml")))
+        .add_source(Environment::with_prefix("app").separator("__"))
+        .build()
+}
+
+impl AppConfig {
+    pub fn load() -> Result<AppConfig, CheckError> {
+        let profile = profile();
+        let mut app_config: AppConfig = check(&profile)?.try_deserialize()?;
+        app_config.db.resolve_password()?;
+        Ok(app_config)
+    }
+}
+
+impl DbConfig {
+    /// Only the `[db]` section, checked alone: binaries without a server don't need the rest
+    pub fn load() -> Result<DbConfig, CheckError> {
+        let mut db: DbConfig = check_section(&profile(), "db")?.get("db")?;
+        db.resolve_password()?;
+        Ok(db)
+    }
+
+    /// Reads the password `password_file` or `password_env` points to into `password`.
+    /// A literal `password` is left as is, the checks reject it in production.
+    fn resolve_password(&mut self) -> Result<(), ConfigError> {
+        let password = match (&self.password, &self.password_file, &self.password_env) {
+            (None, None, None) | (Some(_), None, None) => return Ok(()),
+            (None, Some(path), None) => {
+                let password = std::fs::read_to_string(path).map_err(|e| {
+                    ConfigError::Message(format!(
+                        "db.password_file: cannot read {}: {e}",
+                        path.display()
+                    ))
+                })?;
+                password.trim_end_matches(['\r', '\n']).to_string()
+            }
+            (None, None, Some(var)) => std::env::var(var)
+                .map_err(|e| ConfigError::Message(format!("db.password_env: {var}: {e}")))?,
+            _ => {
+                return Err(ConfigError::Message(
+                    "only one of db.password, db.password_file and db.password_env can be set"
+                        .into(),
+                ));
+            }
+        };
+        self.password = Some(Secret::new(password));
+        Ok(())
+    }
+
+    pub fn connect_options(&self) -> PgConnectOptions {
+        let options = PgConnectOptions::new()
+            .host(&self.host)
+            .port(self.port)
+            .username(&self.login)
+            .database(&self.database)
+            .ssl_mode(self.ssl_mode.into());
+        match &self.password {
+            Some(password) => options.password(password.expose()),
+            None => options,
+        }
+    }
+
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.pool_size)
+            .acquire_timeout(Duration::from_secs(self.acquire_timeout_secs))
+    }
+
+    pub async fn connect(&self) -> Result<PgPool, sqlx::Error> {
+        self.pool_options()
+            .connect_with(self.connect_options())
+            .await
+    }
+}
+
+impl From<SslMode> for PgSslMode {
+    fn from(mode: SslMode) -> Self {
+        match mode {
+            SslMode::Disable => PgSslMode::Disable,
+            SslMode::Allow => PgSslMode::Allow,
+            SslMode::Prefer => PgSslMode::Prefer,
+            SslMode::Require => PgSslMode::Require,
+            SslMode::VerifyCa => PgSslMode::VerifyCa,
+            SslMode::VerifyFull => PgSslMode::VerifyFull,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn db_config() -> DbConfig {
+        load_profile("dev").unwrap().get("db").unwrap()
+    }
+
+    #[test]
+    fn test_resolve_password() {
+        let mut db = db_config();
+        db.resolve_password().unwrap();
+        assert_eq!(db.password.as_ref().unwrap().expose(), "1111");
+        assert!(!format!("{db:?}").contains("1111"));
+
+        let path = std::env::temp_dir().join("app_config_test_password");
+        std::fs::write(&path, "from-file\n").unwrap();
+        let mut db = DbConfig {
+            password: None,
+            password_file: Some(path.clone()),
+            ..db_config()
+        };
+        let resolved = db.resolve_password();
+        std::fs::remove_file(&path).unwrap();
+        resolved.unwrap();
+        assert_eq!(db.password.unwrap().expose(), "from-file");
+
+        let mut db = DbConfig {
+            password: None,
+            password_env: Some("APP_CONFIG_TEST_MISSING_PASSWORD".into()),
+            ..db_config()
+        };
+        assert!(db.resolve_password().is_err());
+
+        let mut db = DbConfig {
+            password_env: Some("PATH".into()),
+            ..db_config()
+        };
+        assert!(db.resolve_password().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Validation of the merged settings before they are deserialized, and the effective
+//! settings of a profile with the source of every value
+
+use std::{collections::BTreeMap, fmt};
+
+use config::{Config, ConfigError, File, Value, ValueKind};
+
+use super::{is_production, load_profile};
+
+/// Checked in this order, every failed rule is reported
+const RULES: &[Rule] = &[
+    Rule::NonEmpty("db.host"),
+    Rule::Range("db.port", 1, 65535),
+    Rule::NonEmpty("db.login"),
+    Rule::NonEmpty("db.database"),
+    Rule::Range("db.pool_size", 1, 1000),
+    Rule::Range("db.acquire_timeout_secs", 1, 600),
+    Rule::OneOf(
+        "db.ssl_mode",
+        &[
+            "disable",
+            "allow",
+            "prefer",
+            "require",
+            "verify-ca",
+            "verify-full",
+        ],
+    ),
+    Rule::Range("server.listen_port", 1, 65535),
+];
+
+/// Sections the profile file has to define itself instead of inheriting them from
+/// `config/default.toml`
+const PROFILE_SECTIONS: &[(&str, &[&str])] = &[
+    ("dev", &["db", "server", "http"]),
+    ("prod", &["db", "server"]),
+];
+
+/// Last key segments whose values are redacted, and rejected as literals in production
+/// unless they come from the environment, e.g. `APP__DB__PASSWORD`
+const SECRET_KEYS: &[&str] = &["password", "secret", "token"];
+
+// origin the config crate gives to the values of `Environment`
+const ENVIRONMENT_SOURCE: &str = "the environment";
+
+const REDACTED: &str = "[REDACTED]";
+
+enum Rule {
+    NonEmpty(&'static str),
+    Range(&'static str, i64, i64),
+    OneOf(&'static str, &'static [&'static str]),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub key: String,
+    pub message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CheckError {
+    #[error("cannot load configuration: {0}")]
+    Load(#[from] ConfigError),
+    #[error("invalid configuration:{}", format_violations(.0))]
+    Invalid(Vec<Violation>),
+}
+
+/// A leaf of the merged settings, e.g. `db.host`
+#[derive(Debug)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    /// File name or `the environment`
+    pub source: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    OnlyLeft {
+        key: String,
+        value: String,
+    },
+    OnlyRight {
+        key: String,
+        value: String,
+    },
+    Changed {
+        key: String,
+        left: String,
+        right: String,
+    },
+}
+
+/// Merged settings of `profile` that pass all the rules
+pub fn check(profile: &str) -> Result<Config, CheckError> {
+    check_keys(profile, |_| true)
+}
+
+/// Merged settings of `profile` whose `section` passes its rules, the other sections
+/// aren't checked
+pub fn check_section(profile: &str, section: &str) -> Result<Config, CheckError> {
+    check_keys(profile, |key| {
+        key.strip_prefix(section)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+fn check_keys(profile: &str, checked: impl Fn(&str) -> bool) -> Result<Config, CheckError> {
+    let merged = load_profile(profile)?;
+    let profile_file = Config::builder()
+        .add_source(File::with_name(&format!("config/{profile}.toml")))
+        .build()?;
+
+    let mut violations = validate(profile, &profile_file, &merged);
+    violations.retain(|v| checked(&v.key));
+    if !violations.is_empty() {
+        return Err(CheckError::Invalid(violations));
+    }
+    Ok(merged)
+}
+
+pub fn validate(profile: &str, profile_file: &Config, merged: &Config) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut violation = |key: &str, message: String| {
+        violations.push(Violation {
+            key: key.to_string(),
+            message,
+        })
+    };
+
+    let sections = PROFILE_SECTIONS
+        .iter()
+        .find(|(name, _)| *name == profile)
+        .map_or(&[][..], |(_, sections)| sections);
+    for section in sections {
+        if profile_file.get_table(section).is_err() {
+            violation(
+                section,
+                format!("section is required in config/{profile}.toml"),
+            );
+        }
+    }
+
+    for rule in RULES {
+        match *rule {
+            Rule::NonEmpty(key) => match merged.get_string(key) {
+                Ok(value) if value.trim().is_empty() => {
+                    violation(key, "cannot be empty".into());
+                }
+                Ok(_) => {}
+                Err(e) => violation(key, describe(e)),
+            },
+            Rule::Range(key, min, max) => match merged.get_int(key) {
+                Ok(value) if value < min || value > max => {
+                    violation(key, format!("{value} is not between {min} and {max}"));
+                }
+                Ok(_) => {}
+                Err(e) => violation(key, describe(e)),
+            },
+            Rule::OneOf(key, allowed) => match merged.get_string(key) {
+                Ok(value) if !allowed.contains(&value.as_str()) => {
+                    violation(
+                        key,
+                        format!("'{value}' is not one of {}", allowed.join(", ")),
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => violation(key, describe(e)),
+            },
+        }
+    }
+
+    if is_production(profile) {
+        for entry in entries(merged) {
+            if is_secret(&entry.key) && entry.source != ENVIRONMENT_SOURCE {
+                violation(
+                    &entry.key,
+                    format!(
+                        "literal secrets are not allowed in the {profile} profile, \
+                         use {0}_file or {0}_env",
+                        entry.key
+                    ),
+                );
+            }
+        }
+    }
+    violations
+}
+
+/// All the leaves of `cfg` sorted by key, secrets redacted
+pub fn entries(cfg: &Config) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    flatten("", &cfg.cache, &mut entries);
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+/// Differences of the values, sources are not compared
+pub fn diff(left: &[Entry], right: &[Entry]) -> Vec<Difference> {
+    let mut keys: BTreeMap<&str, (Option<&str>, Option<&str>)> = BTreeMap::new();
+    for entry in left {
+        keys.entry(&entry.key).or_default().0 = Some(&entry.value);
+    }
+    for entry in right {
+        keys.entry(&entry.key).or_default().1 = Some(&entry.value);
+    }
+
+    keys.into_iter()
+        .filter_map(|(key, values)| {
+            let key = key.to_string();
+            match values {
+                (Some(left), Some(right)) if left == right => None,
+                (Some(left), Some(right)) => Some(Difference::Changed {
+                    key,
+                    left: left.to_string(),
+                    right: right.to_string(),
+                }),
+                (Some(value), None) => Some(Difference::OnlyLeft {
+                    key,
+                    value: value.to_string(),
+                }),
+                (None, Some(value)) => Some(Difference::OnlyRight {
+                    key,
+                    value: value.to_string(),
+                }),
+                (None, None) => None,
+            }
+        })
+        .collect()
+}
+
+fn flatten(prefix: &str, value: &Value, entries: &mut Vec<Entry>) {
+    match &value.kind {
+        ValueKind::Table(table) => {
+            for (name, value) in table {
+                let key = if prefix.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{prefix}.{name}")
+                };
+                flatten(&key, value, entries);
+            }
+        }
+        _ => entries.push(Entry {
+            value: if is_secret(prefix) {
+                REDACTED.to_string()
+            } else {
+                format_value(value)
+            },
+            key: prefix.to_string(),
+            source: value.origin().unwrap_or("unknown").to_string(),
+        }),
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match &value.kind {
+        ValueKind::String(s) => format!("{s:?}"),
+        ValueKind::Array(items) => {
+            let items: Vec<String> = items.iter().map(format_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        kind => kind.to_string(),
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    let name = key.rsplit('.').next().unwrap_or(key);
+    SECRET_KEYS.contains(&name)
+}
+
+fn describe(e: ConfigError) -> String {
+    match e {
+        ConfigError::NotFound(_) => "is missing".into(),
+        e => e.to_string(),
+    }
+}
+
+fn format_violations(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(|v| format!("\n  {}: {}", v.key, v.message))
+        .collect()
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::OnlyLeft { key, value } => write!(f, "- {key} = {value}"),
+            Difference::OnlyRight { key, value } => write!(f, "+ {key} = {value}"),
+            Difference::Changed { key, left, right } => {
+                write!(f, "~ {key} = {left} -> {right}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::{Environment, FileFormat};
+    use std::collections::HashMap;
+
+    fn toml(text: &str) -> Config {
+        Config::builder()
+            .add_source(File::from_str(text, FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let merged = toml(
+            r#"
+            [db]
+            host = " "
+            port = 70000
+            login = "postgres"
+            password = "1111"
+            database = "mydb"
+            pool_size = 10
+            acquire_timeout_secs = 5
+            ssl_mode = "sometimes"
+            "#,
+        );
+        let keys = |violations: Vec<Violation>| -> Vec<String> {
+            violations.into_iter().map(|v| v.key).collect()
+        };
+
+        assert_eq!(
+            keys(validate("dev", &merged, &merged)),
+            [
+                "server",
+                "http",
+                "db.host",
+                "db.port",
+                "db.ssl_mode",
+                "server.listen_port"
+            ]
+        );
+        assert_eq!(
+            keys(validate("prod", &merged, &merged)),
+            [
+                "server",
+                "db.host",
+                "db.port",
+                "db.ssl_mode",
+                "server.listen_port",
+                "db.password"
+            ]
+        );
+
+        // a password set by APP__DB__PASSWORD isn't a literal of the files
+        let with_env = Config::builder()
+            .add_source(File::from_str(
+                "[server]\nlisten_port = 80",
+                FileFormat::Toml,
+            ))
+            .add_source(Environment::with_prefix("app").separator("__").source(Some(
+                HashMap::from([("APP__DB__PASSWORD".to_string(), "1111".to_string())]),
+            )))
+            .build()
+            .unwrap();
+        let violations = validate("prod", &with_env, &with_env);
+        assert!(violations.iter().all(|v| v.key != "db.password"));
+    }
+
+    #[test]
+    fn test_entries_and_diff() {
+        let left = entries(&toml("[db]\nhost = \"a\"\npassword = \"1111\"\nport = 1"));
+        let right = entries(&toml(
+            "[db]\nhost = \"b\"\nport = 1\n[server]\nlisten_port = 2",
+        ));
+        assert_eq!(left[1].key, "db.password");
+        assert_eq!(left[1].value, REDACTED);
+
+        let lines: Vec<String> = diff(&left, &right).iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                r#"~ db.host = "a" -> "b""#,
+                "- db.password = [REDACTED]",
+                "+ server.listen_port = 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_files() {
+        check("dev").unwrap();
+        // prod.toml keeps its password out of the file
+        check("prod").unwrap();
+        check_section("prod", "db").unwrap();
+    }
+}
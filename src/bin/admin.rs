@@ -0,0 +1,119 @@
+use std::process::ExitCode;
+
+use rust_demo1::{
+    app_config::{self, DbConfig},
+    migrate,
+};
+
+const USAGE: &str = "usage:
+  admin migrate <status|up|down|redo>
+  admin config check [--profile <profile>]
+  admin config diff <profile> <profile>";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["migrate", command] => migrate_command(command).await,
+        ["config", "check"] => config_check(&app_config::profile()),
+        ["config", "check", "--profile", profile] => config_check(profile),
+        ["config", "diff", left, right] => config_diff(left, right),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn migrate_command(command: &str) -> ExitCode {
+    let db = match DbConfig::load() {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("cannot load the db config: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let pool = match db
+        .pool_options()
+        .max_connections(1)
+        .connect_with(db.connect_options())
+        .await
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("cannot connect to the database: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match command {
+        "status" => migrate::status(&pool).await.map(|migrations| {
+            for m in migrations {
+                let state = if m.applied { "applied" } else { "pending" };
+                println!("{:>4} {:<8} {}", m.version, state, m.description);
+            }
+        }),
+        "up" => migrate::up(&pool)
+            .await
+            .map(|()| println!("all migrations applied")),
+        "down" => migrate::down(&pool).await.map(|reverted| match reverted {
+            Some(version) => println!("reverted {version}"),
+            None => println!("nothing to revert"),
+        }),
+        "redo" => migrate::redo(&pool).await.map(|redone| match redone {
+            Some(version) => println!("reverted and applied {version}"),
+            None => println!("nothing to redo"),
+        }),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("migration failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints the effective settings of the profile, with the source of every value
+fn config_check(profile: &str) -> ExitCode {
+    match app_config::check(profile) {
+        Ok(cfg) => {
+            let entries = app_config::entries(&cfg);
+            let width = entries.iter().map(|e| e.key.len() + e.value.len()).max();
+            for entry in &entries {
+                let line = format!("{} = {}", entry.key, entry.value);
+                println!("{line:<0$}  # {1}", width.unwrap_or(0) + 3, entry.source);
+            }
+            println!("profile {profile}: ok");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("profile {profile}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn config_diff(left: &str, right: &str) -> ExitCode {
+    let load = |profile: &str| {
+        app_config::load_profile(profile)
+            .map(|cfg| app_config::entries(&cfg))
+            .map_err(|e| eprintln!("profile {profile}: {e}"))
+    };
+    let (Ok(left_entries), Ok(right_entries)) = (load(left), load(right)) else {
+        return ExitCode::FAILURE;
+    };
+
+    println!("--- {left}\n+++ {right}");
+    for difference in app_config::diff(&left_entries, &right_entries) {
+        println!("{difference}");
+    }
+    ExitCode::SUCCESS
+}
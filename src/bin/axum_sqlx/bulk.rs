@@ -0,0 +1,212 @@
+//! CSV and JSON Lines formats of the account import and export, one account per line
+
+use axum::body::Bytes;
+use bigdecimal::BigDecimal;
+use serde::Deserialize;
+
+use crate::persist::{Account, NewAccount};
+
+// accounts.owner_name is VARCHAR(255)
+const MAX_OWNER_NAME_CHARS: usize = 255;
+// accounts.balance is NUMERIC(10, 2)
+const MAX_BALANCE_SCALE: i64 = 2;
+const MAX_BALANCE_INTEGER_DIGITS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub enum BulkFormat {
+    #[default]
+    #[serde(rename = "csv")]
+    Csv,
+    #[serde(rename = "jsonl")]
+    JsonLines,
+}
+
+impl BulkFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "text/csv" => Some(BulkFormat::Csv),
+            "application/x-ndjson" | "application/jsonl" | "application/x-jsonlines" => {
+                Some(BulkFormat::JsonLines)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "text/csv",
+            BulkFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            BulkFormat::Csv => "accounts.csv",
+            BulkFormat::JsonLines => "accounts.jsonl",
+        }
+    }
+
+    /// First line of an export
+    pub fn header(self) -> Option<Bytes> {
+        match self {
+            BulkFormat::Csv => Some(Bytes::from_static(b"id,owner_name,balance\n")),
+            BulkFormat::JsonLines => None,
+        }
+    }
+
+    pub fn encode(self, account: &Account) -> Bytes {
+        match self {
+            BulkFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                writer
+                    .serialize(account)
+                    .expect("account is serializable to CSV");
+                Bytes::from(writer.into_inner().expect("writing to a Vec never fails"))
+            }
+            BulkFormat::JsonLines => {
+                let mut line = serde_json::to_vec(account).expect("account is serializable");
+                line.push(b'\n');
+                Bytes::from(line)
+            }
+        }
+    }
+}
+
+/// Turns the lines of an import into accounts, other columns or fields (like `id` of an
+/// export) are ignored
+pub enum RowParser {
+    Csv { owner_name: usize, balance: usize },
+    JsonLines,
+}
+
+#[derive(Deserialize)]
+struct JsonRow {
+    owner_name: String,
+    balance: BigDecimal,
+}
+
+impl RowParser {
+    /// `None` for CSV without an `owner_name,balance` header
+    pub fn csv(header: &str) -> Option<Self> {
+        let header = parse_csv_line(header).ok()?;
+        let column = |name: &str| header.iter().position(|column| column == name);
+        Some(RowParser::Csv {
+            owner_name: column("owner_name")?,
+            balance: column("balance")?,
+        })
+    }
+
+    pub fn parse(&self, line: &str) -> Result<NewAccount, String> {
+        let (owner_name, balance) = match self {
+            RowParser::Csv {
+                owner_name,
+                balance,
+            } => {
+                let record = parse_csv_line(line)?;
+                let field = |i: usize, name: &str| {
+                    record
+                        .get(i)
+                        .ok_or_else(|| format!("Missing {name} column"))
+                };
+                let balance = field(*balance, "balance")?
+                    .parse::<BigDecimal>()
+                    .map_err(|_| "Balance is not a number".to_string())?;
+                (field(*owner_name, "owner_name")?.to_string(), balance)
+            }
+            RowParser::JsonLines => {
+                let row: JsonRow = serde_json::from_str(line).map_err(|e| e.to_string())?;
+                (row.owner_name, row.balance)
+            }
+        };
+        validate(owner_name, balance)
+    }
+}
+
+fn parse_csv_line(line: &str) -> Result<csv::StringRecord, String> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes())
+        .into_records()
+        .next()
+        .unwrap_or_else(|| Ok(csv::StringRecord::new()))
+        .map_err(|e| e.to_string())
+}
+
+fn validate(owner_name: String, balance: BigDecimal) -> Result<NewAccount, String> {
+    let owner_name = owner_name.trim().to_string();
+    if owner_name.is_empty() {
+        return Err("Owner name is empty".into());
+    }
+    if owner_name.chars().count() > MAX_OWNER_NAME_CHARS {
+        return Err(format!(
+            "Owner name is longer than {MAX_OWNER_NAME_CHARS} characters"
+        ));
+    }
+    if balance < 0 {
+        return Err("Balance cannot be negative".into());
+    }
+    let balance = balance.normalized();
+    if balance.fractional_digit_count() > MAX_BALANCE_SCALE {
+        return Err(format!(
+            "Balance has more than {MAX_BALANCE_SCALE} decimal places"
+        ));
+    }
+    if balance >= 10u64.pow(MAX_BALANCE_INTEGER_DIGITS) {
+        return Err("Balance is too large".into());
+    }
+    Ok(NewAccount {
+        owner_name,
+        balance,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_rows() {
+        let csv = RowParser::csv("balance, owner_name,id").unwrap();
+        let account = csv.parse(r#"10.5,"Doe, John",7"#).unwrap();
+        assert_eq!(account.owner_name, "Doe, John");
+        assert_eq!(account.balance, BigDecimal::from_str("10.5").unwrap());
+        assert!(RowParser::csv("name,balance").is_none());
+
+        assert_eq!(csv.parse("-1,A").unwrap_err(), "Balance cannot be negative");
+        assert_eq!(
+            csv.parse("1.001,A").unwrap_err(),
+            "Balance has more than 2 decimal places"
+        );
+        assert_eq!(
+            csv.parse("100000000,A").unwrap_err(),
+            "Balance is too large"
+        );
+        assert_eq!(csv.parse("1, ").unwrap_err(), "Owner name is empty");
+        assert_eq!(csv.parse("1").unwrap_err(), "Missing owner_name column");
+
+        let json = RowParser::JsonLines;
+        let account = json
+            .parse(r#"{"id": 1, "owner_name": "A", "balance": "1.10"}"#)
+            .unwrap();
+        assert_eq!(account.balance, BigDecimal::from_str("1.1").unwrap());
+        assert!(json.parse(r#"{"owner_name": "A"}"#).is_err());
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert!(matches!(
+            BulkFormat::from_content_type("text/csv; charset=utf-8"),
+            Some(BulkFormat::Csv)
+        ));
+        assert!(matches!(
+            BulkFormat::from_content_type("application/x-ndjson"),
+            Some(BulkFormat::JsonLines)
+        ));
+        assert!(BulkFormat::from_content_type("application/json").is_none());
+    }
+}
@@ -0,0 +1,1164 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use futures::{stream::BoxStream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{Encode, FromRow, PgPool, Postgres, QueryBuilder, Type};
+use std::{cmp::Ordering, collections::HashSet, fmt, io, str::FromStr, time::Duration};
+
+mod memory;
+
+pub use memory::InMemoryAccountRepository;
+
+type PgTransaction<'c> = sqlx::Transaction<'c, Postgres>;
+
+// constraint names generated by Postgres for migrations/0001_accounts.up.sql
+const OWNER_NAME_UNIQUE: &str = "accounts_owner_name_key";
+
+// https://www.postgresql.org/docs/current/errcodes-appendix.html
+const SERIALIZATION_FAILURE: &str = "40001";
+const DEADLOCK_DETECTED: &str = "40P01";
+
+const IMPORT_BATCH_SIZE: usize = 500;
+
+const TRANSFER_ATTEMPTS: u32 = 5;
+const TRANSFER_BACKOFF: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Account {
+    id: i64,
+    owner_name: String,
+    balance: BigDecimal,
+}
+
+// migrations/0004_transactions_constraints.up.sql
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "transfer_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TransferStatus {
+    Pending,
+    Completed,
+    Failed,
+    Reversed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Transaction {
+    id: i64,
+    amount: BigDecimal,
+    currency: String,
+    status: TransferStatus,
+    src_account_id: i64,
+    dst_account_id: i64,
+    tx_timestamp: NaiveDateTime,
+}
+
+pub struct Transfer {
+    pub src_account_id: i64,
+    pub dst_account_id: i64,
+    pub amount: BigDecimal,
+}
+
+impl Transfer {
+    // stored with the idempotency key to detect its reuse for a different transfer
+    fn fingerprint(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.src_account_id,
+            self.dst_account_id,
+            self.amount.normalized()
+        )
+    }
+}
+
+pub enum TransferOutcome {
+    Created(Transaction),
+    // same Idempotency-Key was already processed, nothing was moved again
+    Replayed(Transaction),
+}
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+// `:` separates the sort value from the id in a cursor, so the value must not end with one
+const CURSOR_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn keyword(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+
+    // rows after the cursor in this order
+    fn after(self) -> &'static str {
+        match self {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        }
+    }
+
+    fn compare<K: Ord>(self, a: &K, b: &K) -> Ordering {
+        match self {
+            SortOrder::Asc => a.cmp(b),
+            SortOrder::Desc => b.cmp(a),
+        }
+    }
+
+    fn is_after<K: Ord>(self, key: &K, cursor: &K) -> bool {
+        match self {
+            SortOrder::Asc => key > cursor,
+            SortOrder::Desc => key < cursor,
+        }
+    }
+}
+
+/// Whitelist of the columns accounts can be sorted by, ties are broken by id
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountSort {
+    #[default]
+    Id,
+    OwnerName,
+    Balance,
+}
+
+impl AccountSort {
+    fn column(self) -> &'static str {
+        match self {
+            AccountSort::Id => "id",
+            AccountSort::OwnerName => "owner_name",
+            AccountSort::Balance => "balance",
+        }
+    }
+
+    fn key(self, acc: &Account) -> AccountKey {
+        match self {
+            AccountSort::Id => AccountKey::Id(acc.id),
+            AccountSort::OwnerName => AccountKey::OwnerName(acc.owner_name.clone(), acc.id),
+            AccountSort::Balance => AccountKey::Balance(acc.balance.clone(), acc.id),
+        }
+    }
+
+    fn parse_key(self, cursor: &str) -> Result<AccountKey, PersistError> {
+        Ok(match self {
+            AccountSort::Id => AccountKey::Id(parse_id(cursor)?),
+            AccountSort::OwnerName => {
+                let (name, id) = parse_cursor(cursor)?;
+                AccountKey::OwnerName(name, id)
+            }
+            AccountSort::Balance => {
+                let (balance, id) = parse_cursor(cursor)?;
+                AccountKey::Balance(balance, id)
+            }
+        })
+    }
+}
+
+/// Position of an account in a listing, its `Display` is the cursor given to clients
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum AccountKey {
+    Id(i64),
+    OwnerName(String, i64),
+    Balance(BigDecimal, i64),
+}
+
+impl fmt::Display for AccountKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountKey::Id(id) => write!(f, "{id}"),
+            AccountKey::OwnerName(name, id) => write!(f, "{name}:{id}"),
+            AccountKey::Balance(balance, id) => write!(f, "{balance}:{id}"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AccountQuery {
+    pub owner_prefix: Option<String>,
+    pub min_balance: Option<BigDecimal>,
+    pub max_balance: Option<BigDecimal>,
+    #[serde(default)]
+    pub sort: AccountSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    /// `next_cursor` of the previous page, it is only valid with the same sort and order
+    pub cursor: Option<String>,
+}
+
+/// Whitelist of the columns transactions can be sorted by, ties are broken by id
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionSort {
+    #[default]
+    Id,
+    TxTimestamp,
+    Amount,
+}
+
+impl TransactionSort {
+    fn column(self) -> &'static str {
+        match self {
+            TransactionSort::Id => "id",
+            TransactionSort::TxTimestamp => "tx_timestamp",
+            TransactionSort::Amount => "amount",
+        }
+    }
+
+    fn key(self, tx: &Transaction) -> TransactionKey {
+        match self {
+            TransactionSort::Id => TransactionKey::Id(tx.id),
+            TransactionSort::TxTimestamp => TransactionKey::TxTimestamp(tx.tx_timestamp, tx.id),
+            TransactionSort::Amount => TransactionKey::Amount(tx.amount.clone(), tx.id),
+        }
+    }
+
+    fn parse_key(self, cursor: &str) -> Result<TransactionKey, PersistError> {
+        Ok(match self {
+            TransactionSort::Id => TransactionKey::Id(parse_id(cursor)?),
+            TransactionSort::TxTimestamp => {
+                let (ts, id) = cursor
+                    .rsplit_once(':')
+                    .and_then(|(ts, id)| {
+                        let ts = NaiveDateTime::parse_from_str(ts, CURSOR_TIMESTAMP_FORMAT);
+                        Some((ts.ok()?, id.parse().ok()?))
+                    })
+                    .ok_or(PersistError::InvalidCursor)?;
+                TransactionKey::TxTimestamp(ts, id)
+            }
+            TransactionSort::Amount => {
+                let (amount, id) = parse_cursor(cursor)?;
+                TransactionKey::Amount(amount, id)
+            }
+        })
+    }
+}
+
+/// Position of a transaction in a listing, its `Display` is the cursor given to clients
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum TransactionKey {
+    Id(i64),
+    TxTimestamp(NaiveDateTime, i64),
+    Amount(BigDecimal, i64),
+}
+
+impl fmt::Display for TransactionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionKey::Id(id) => write!(f, "{id}"),
+            TransactionKey::TxTimestamp(ts, id) => {
+                write!(f, "{}:{id}", ts.format(CURSOR_TIMESTAMP_FORMAT))
+            }
+            TransactionKey::Amount(amount, id) => write!(f, "{amount}:{id}"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TransactionQuery {
+    /// Transactions where the account is either the source or the destination
+    pub account_id: Option<i64>,
+    /// Inclusive
+    pub from: Option<NaiveDateTime>,
+    /// Exclusive
+    pub to: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub sort: TransactionSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    /// `next_cursor` of the previous page, it is only valid with the same sort and order
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    items: Vec<T>,
+    /// `None` on the last page
+    next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    // `rows` were fetched with one extra row to find out whether there is a next page
+    fn new(mut rows: Vec<T>, limit: i64, cursor_of: impl Fn(&T) -> String) -> Self {
+        let next_cursor = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(cursor_of)
+        } else {
+            None
+        };
+        Page {
+            items: rows,
+            next_cursor,
+        }
+    }
+}
+
+/// Already validated row of an import
+#[derive(Debug)]
+pub struct NewAccount {
+    pub owner_name: String,
+    pub balance: BigDecimal,
+}
+
+/// Numbered from 1, counting the CSV header and blank lines
+pub type ImportRow = (u64, NewAccount);
+
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub line: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: u64,
+    /// Rows that were skipped, the others are imported
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct BalanceMismatch {
+    account_id: i64,
+    balance: BigDecimal,
+    ledger_balance: BigDecimal,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistError {
+    #[error("Account {0} not found")]
+    NotFound(i64),
+    #[error("Account of '{0}' already exists")]
+    DuplicateOwner(String),
+    #[error("Insufficient funds on account {0}")]
+    InsufficientFunds(i64),
+    #[error("Account {0} has transactions")]
+    AccountInUse(i64),
+    #[error("Idempotency key '{0}' was already used for another transfer")]
+    IdempotencyKeyReused(String),
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error("Cannot read the import: {0}")]
+    ImportRead(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Db(#[from] sqlx::Error),
+}
+
+/// Accounts and transfers as seen by the HTTP handlers
+#[async_trait::async_trait]
+pub trait AccountRepository: Send + Sync {
+    async fn list_accounts(&self, query: &AccountQuery) -> Result<Page<Account>, PersistError>;
+    async fn fetch_account(&self, id: i64) -> Result<Account, PersistError>;
+    async fn create_account(
+        &self,
+        owner_name: &str,
+        initial_balance: BigDecimal,
+    ) -> Result<Account, PersistError>;
+    async fn update_account(&self, id: i64, owner_name: &str) -> Result<Account, PersistError>;
+    async fn delete_account(&self, id: i64) -> Result<(), PersistError>;
+    async fn list_transactions(
+        &self,
+        query: &TransactionQuery,
+    ) -> Result<Page<Transaction>, PersistError>;
+    async fn make_transfer(
+        &self,
+        transfer: &Transfer,
+        idempotency_key: Option<&str>,
+    ) -> Result<TransferOutcome, PersistError>;
+    async fn reconcile_ledger(&self) -> Result<Vec<BalanceMismatch>, PersistError>;
+    /// Creates accounts from `rows` in one transaction, an owner that already exists is
+    /// reported as a row error. A read error of `rows` cancels the whole import.
+    async fn import_accounts(
+        &self,
+        rows: BoxStream<'_, io::Result<ImportRow>>,
+    ) -> Result<ImportReport, PersistError>;
+    /// All accounts ordered by id, read lazily
+    fn export_accounts(&self) -> BoxStream<'static, Result<Account, PersistError>>;
+}
+
+pub struct PgAccountRepository {
+    db: PgPool,
+}
+
+impl PgAccountRepository {
+    pub fn new(db: PgPool) -> Self {
+        PgAccountRepository { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl AccountRepository for PgAccountRepository {
+    async fn list_accounts(&self, query: &AccountQuery) -> Result<Page<Account>, PersistError> {
+        list_accounts(&self.db, query).await
+    }
+
+    async fn fetch_account(&self, id: i64) -> Result<Account, PersistError> {
+        fetch_account(&self.db, id).await
+    }
+
+    async fn create_account(
+        &self,
+        owner_name: &str,
+        initial_balance: BigDecimal,
+    ) -> Result<Account, PersistError> {
+        create_accounts(&self.db, owner_name, initial_balance).await
+    }
+
+    async fn update_account(&self, id: i64, owner_name: &str) -> Result<Account, PersistError> {
+        update_account(&self.db, id, owner_name).await
+    }
+
+    async fn delete_account(&self, id: i64) -> Result<(), PersistError> {
+        delete_account(&self.db, id).await
+    }
+
+    async fn list_transactions(
+        &self,
+        query: &TransactionQuery,
+    ) -> Result<Page<Transaction>, PersistError> {
+        list_transactions(&self.db, query).await
+    }
+
+    async fn make_transfer(
+        &self,
+        transfer: &Transfer,
+        idempotency_key: Option<&str>,
+    ) -> Result<TransferOutcome, PersistError> {
+        make_transfer(&self.db, transfer, idempotency_key).await
+    }
+
+    async fn reconcile_ledger(&self) -> Result<Vec<BalanceMismatch>, PersistError> {
+        reconcile_ledger(&self.db).await
+    }
+
+    async fn import_accounts(
+        &self,
+        rows: BoxStream<'_, io::Result<ImportRow>>,
+    ) -> Result<ImportReport, PersistError> {
+        import_accounts(&self.db, rows).await
+    }
+
+    fn export_accounts(&self) -> BoxStream<'static, Result<Account, PersistError>> {
+        export_accounts(self.db.clone())
+    }
+}
+
+fn violated_constraint(e: &sqlx::Error) -> Option<&str> {
+    e.as_database_error().and_then(|e| e.constraint())
+}
+
+fn map_owner_error(e: sqlx::Error, owner_name: &str) -> PersistError {
+    match violated_constraint(&e) {
+        Some(OWNER_NAME_UNIQUE) => PersistError::DuplicateOwner(owner_name.to_string()),
+        _ => PersistError::Db(e),
+    }
+}
+
+fn page_size(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+// `%` and `_` typed by the user are matched literally
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn parse_id(cursor: &str) -> Result<i64, PersistError> {
+    cursor.parse().map_err(|_| PersistError::InvalidCursor)
+}
+
+// `value:id`, the value itself may contain `:`
+fn parse_cursor<T: FromStr>(cursor: &str) -> Result<(T, i64), PersistError> {
+    cursor
+        .rsplit_once(':')
+        .and_then(|(value, id)| Some((value.parse().ok()?, id.parse().ok()?)))
+        .ok_or(PersistError::InvalidCursor)
+}
+
+fn push_after_id(qb: &mut QueryBuilder<'_, Postgres>, id: i64, order: SortOrder) {
+    qb.push(format_args!(" AND id {} ", order.after()))
+        .push_bind(id);
+}
+
+// keyset condition: rows that come after (value, id) when sorted by (column, id)
+fn push_after<'a, T>(
+    qb: &mut QueryBuilder<'a, Postgres>,
+    column: &'static str,
+    value: T,
+    id: i64,
+    order: SortOrder,
+) where
+    T: 'a + Encode<'a, Postgres> + Type<Postgres> + Send,
+{
+    qb.push(format_args!(" AND ({column}, id) {} (", order.after()))
+        .push_bind(value)
+        .push(", ")
+        .push_bind(id)
+        .push(")");
+}
+
+fn push_order_limit(
+    qb: &mut QueryBuilder<'_, Postgres>,
+    column: &'static str,
+    order: SortOrder,
+    limit: i64,
+) {
+    let order = order.keyword();
+    if column == "id" {
+        qb.push(format_args!(" ORDER BY id {order}"));
+    } else {
+        qb.push(format_args!(" ORDER BY {column} {order}, id {order}"));
+    }
+    qb.push(" LIMIT ").push_bind(limit + 1);
+}
+
+/// One page of accounts matching the filters, after `cursor` in the requested order
+pub async fn list_accounts(
+    db: &PgPool,
+    query: &AccountQuery,
+) -> Result<Page<Account>, PersistError> {
+    let limit = page_size(query.limit);
+    let mut qb = QueryBuilder::new("SELECT id, owner_name, balance FROM accounts WHERE TRUE");
+    if let Some(prefix) = &query.owner_prefix {
+        qb.push(" AND owner_name LIKE ")
+            .push_bind(format!("{}%", escape_like(prefix)));
+    }
+    if let Some(min) = &query.min_balance {
+        qb.push(" AND balance >= ").push_bind(min.clone());
+    }
+    if let Some(max) = &query.max_balance {
+        qb.push(" AND balance <= ").push_bind(max.clone());
+    }
+    if let Some(cursor) = &query.cursor {
+        match query.sort.parse_key(cursor)? {
+            AccountKey::Id(id) => push_after_id(&mut qb, id, query.order),
+            AccountKey::OwnerName(name, id) => {
+                push_after(&mut qb, "owner_name", name, id, query.order)
+            }
+            AccountKey::Balance(balance, id) => {
+                push_after(&mut qb, "balance", balance, id, query.order)
+            }
+        }
+    }
+    push_order_limit(&mut qb, query.sort.column(), query.order, limit);
+
+    let accounts = qb.build_query_as().fetch_all(db).await?;
+    Ok(Page::new(accounts, limit, |acc: &Account| {
+        query.sort.key(acc).to_string()
+    }))
+}
+
+pub async fn fetch_account(db: &PgPool, id: i64) -> Result<Account, PersistError> {
+    sqlx::query_as("SELECT id, owner_name, balance FROM accounts WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(PersistError::NotFound(id))
+}
+
+pub async fn create_accounts(
+    db: &PgPool,
+    owner_name: &str,
+    initial_balance: BigDecimal,
+) -> Result<Account, PersistError> {
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO accounts(owner_name, balance) VALUES($1, $2)")
+        .bind(owner_name)
+        .bind(&initial_balance)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| map_owner_error(e, owner_name))?;
+
+    // opening balance, so that the ledger sums up to accounts.balance
+    sqlx::query(
+        "INSERT INTO ledger_entries(account_id, amount) VALUES(currval('accounts_seq'), $1)",
+    )
+    .bind(&initial_balance)
+    .execute(&mut *tx)
+    .await?;
+
+    let result = sqlx::query_as(
+        r#"
+        SELECT id, owner_name, balance
+        FROM accounts
+        WHERE id = currval('accounts_seq')
+    "#,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(result)
+}
+
+pub async fn import_accounts(
+    db: &PgPool,
+    mut rows: BoxStream<'_, io::Result<ImportRow>>,
+) -> Result<ImportReport, PersistError> {
+    let mut report = ImportReport::default();
+    // ON CONFLICT cannot tell which of two equal owners in one batch was inserted
+    let mut seen = HashSet::new();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+
+    let mut tx = db.begin().await?;
+    while let Some((line, account)) = rows.try_next().await? {
+        if !seen.insert(account.owner_name.clone()) {
+            report.errors.push(RowError {
+                line,
+                error: PersistError::DuplicateOwner(account.owner_name).to_string(),
+            });
+            continue;
+        }
+        batch.push((line, account));
+        if batch.len() == IMPORT_BATCH_SIZE {
+            insert_batch(&mut tx, &batch, &mut report).await?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        insert_batch(&mut tx, &batch, &mut report).await?;
+    }
+    tx.commit().await?;
+
+    report.errors.sort_by_key(|e| e.line);
+    Ok(report)
+}
+
+async fn insert_batch(
+    tx: &mut PgTransaction<'_>,
+    batch: &[ImportRow],
+    report: &mut ImportReport,
+) -> Result<(), PersistError> {
+    let mut qb = QueryBuilder::new("INSERT INTO accounts(owner_name, balance) ");
+    qb.push_values(batch, |mut row, (_, account)| {
+        row.push_bind(&account.owner_name)
+            .push_bind(&account.balance);
+    });
+    qb.push(" ON CONFLICT (owner_name) DO NOTHING RETURNING id, owner_name, balance");
+    let inserted: Vec<Account> = qb.build_query_as().fetch_all(&mut **tx).await?;
+
+    if !inserted.is_empty() {
+        // opening balances, see create_accounts
+        let mut qb = QueryBuilder::new("INSERT INTO ledger_entries(account_id, amount) ");
+        qb.push_values(&inserted, |mut row, account| {
+            row.push_bind(account.id).push_bind(&account.balance);
+        });
+        qb.build().execute(&mut **tx).await?;
+    }
+
+    let inserted_owners: HashSet<&str> =
+        inserted.iter().map(|acc| acc.owner_name.as_str()).collect();
+    for (line, account) in batch {
+        if !inserted_owners.contains(account.owner_name.as_str()) {
+            report.errors.push(RowError {
+                line: *line,
+                error: PersistError::DuplicateOwner(account.owner_name.clone()).to_string(),
+            });
+        }
+    }
+    report.imported += inserted.len() as u64;
+    Ok(())
+}
+
+/// Streams the rows as they come from Postgres instead of loading them all
+pub fn export_accounts(db: PgPool) -> BoxStream<'static, Result<Account, PersistError>> {
+    Box::pin(async_stream::try_stream! {
+        let mut accounts =
+            sqlx::query_as("SELECT id, owner_name, balance FROM accounts ORDER BY id").fetch(&db);
+        while let Some(account) = accounts.try_next().await? {
+            yield account;
+        }
+    })
+}
+
+pub async fn update_account(
+    db: &PgPool,
+    id: i64,
+    owner_name: &str,
+) -> Result<Account, PersistError> {
+    sqlx::query_as(
+        r#"
+        UPDATE accounts SET owner_name = $1
+        WHERE id = $2
+        RETURNING id, owner_name, balance
+    "#,
+    )
+    .bind(owner_name)
+    .bind(id)
+    .fetch_optional(db)
+    .await
+    .map_err(|e| map_owner_error(e, owner_name))?
+    .ok_or(PersistError::NotFound(id))
+}
+
+pub async fn delete_account(db: &PgPool, id: i64) -> Result<(), PersistError> {
+    let mut tx = db.begin().await?;
+    // transfer entries stay, they make the account "in use"
+    sqlx::query("DELETE FROM ledger_entries WHERE account_id = $1 AND transaction_id IS NULL")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    let result = sqlx::query("DELETE FROM accounts WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            if e.as_database_error()
+                .is_some_and(|e| e.is_foreign_key_violation())
+            {
+                PersistError::AccountInUse(id)
+            } else {
+                PersistError::Db(e)
+            }
+        })?;
+    if result.rows_affected() == 0 {
+        return Err(PersistError::NotFound(id));
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// One page of transactions matching the filters, after `cursor` in the requested order
+pub async fn list_transactions(
+    db: &PgPool,
+    query: &TransactionQuery,
+) -> Result<Page<Transaction>, PersistError> {
+    let limit = page_size(query.limit);
+    let mut qb = QueryBuilder::new(
+        r#"
+        SELECT id, amount, currency, status, src_account_id, dst_account_id, tx_timestamp
+        FROM transactions
+        WHERE TRUE"#,
+    );
+    if let Some(account_id) = query.account_id {
+        qb.push(" AND (src_account_id = ")
+            .push_bind(account_id)
+            .push(" OR dst_account_id = ")
+            .push_bind(account_id)
+            .push(")");
+    }
+    if let Some(from) = query.from {
+        qb.push(" AND tx_timestamp >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+        qb.push(" AND tx_timestamp < ").push_bind(to);
+    }
+    if let Some(cursor) = &query.cursor {
+        match query.sort.parse_key(cursor)? {
+            TransactionKey::Id(id) => push_after_id(&mut qb, id, query.order),
+            TransactionKey::TxTimestamp(ts, id) => {
+                push_after(&mut qb, "tx_timestamp", ts, id, query.order)
+            }
+            TransactionKey::Amount(amount, id) => {
+                push_after(&mut qb, "amount", amount, id, query.order)
+            }
+        }
+    }
+    push_order_limit(&mut qb, query.sort.column(), query.order, limit);
+
+    let transactions = qb.build_query_as().fetch_all(db).await?;
+    Ok(Page::new(transactions, limit, |tx: &Transaction| {
+        query.sort.key(tx).to_string()
+    }))
+}
+
+/// Moves money between accounts, records the transaction and its ledger entries, all in
+/// one DB transaction. With an idempotency key a repeated call returns the transaction
+/// of the first one; a concurrent call with the same key waits on the key's row lock.
+/// Deadlocks and serialization failures are retried a few times with a growing pause.
+pub async fn make_transfer(
+    db: &PgPool,
+    transfer: &Transfer,
+    idempotency_key: Option<&str>,
+) -> Result<TransferOutcome, PersistError> {
+    let mut attempt = 1;
+    loop {
+        match try_transfer(db, transfer, idempotency_key).await {
+            Err(PersistError::Db(e)) if is_retryable(&e) && attempt < TRANSFER_ATTEMPTS => {
+                let backoff = TRANSFER_BACKOFF * 2u32.pow(attempt - 1);
+                // jitter, so that the conflicting transfers don't retry in lockstep
+                let jitter =
+                    Duration::from_millis(rand::random_range(0..=backoff.as_millis() as u64));
+                tracing::warn!(error = %e, attempt, "transfer conflict, retrying");
+                tokio::time::sleep(backoff + jitter).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_retryable(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| code == SERIALIZATION_FAILURE || code == DEADLOCK_DETECTED)
+}
+
+async fn try_transfer(
+    db: &PgPool,
+    transfer: &Transfer,
+    idempotency_key: Option<&str>,
+) -> Result<TransferOutcome, PersistError> {
+    let mut tx = db.begin().await?;
+
+    if let Some(key) = idempotency_key {
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO idempotency_keys(idempotency_key, request_fingerprint)
+            VALUES ($1, $2)
+            ON CONFLICT (idempotency_key) DO NOTHING
+        "#,
+        )
+        .bind(key)
+        .bind(transfer.fingerprint())
+        .execute(&mut *tx)
+        .await?;
+
+        if inserted.rows_affected() == 0 {
+            let (fingerprint, transaction_id): (String, i64) = sqlx::query_as(
+                r#"
+                SELECT request_fingerprint, transaction_id
+                FROM idempotency_keys
+                WHERE idempotency_key = $1
+            "#,
+            )
+            .bind(key)
+            .fetch_one(&mut *tx)
+            .await?;
+            if fingerprint != transfer.fingerprint() {
+                return Err(PersistError::IdempotencyKeyReused(key.to_string()));
+            }
+            let transaction = fetch_transaction(&mut tx, transaction_id).await?;
+            return Ok(TransferOutcome::Replayed(transaction));
+        }
+    }
+
+    // rows are locked in ascending id order whatever the transfer direction,
+    // so that A->B and B->A running at the same time cannot deadlock
+    let account_ids = [transfer.src_account_id, transfer.dst_account_id];
+    let locked: Vec<(i64, BigDecimal)> = sqlx::query_as(
+        "SELECT id, balance FROM accounts WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+    )
+    .bind(account_ids)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let src_balance = locked
+        .iter()
+        .find(|(id, _)| *id == transfer.src_account_id)
+        .map(|(_, balance)| balance)
+        .ok_or(PersistError::NotFound(transfer.src_account_id))?;
+    if !locked.iter().any(|(id, _)| *id == transfer.dst_account_id) {
+        return Err(PersistError::NotFound(transfer.dst_account_id));
+    }
+    if *src_balance < transfer.amount {
+        return Err(PersistError::InsufficientFunds(transfer.src_account_id));
+    }
+
+    sqlx::query("UPDATE accounts SET balance = balance - $1 WHERE id = $2")
+        .bind(&transfer.amount)
+        .bind(transfer.src_account_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE accounts SET balance = balance + $1 WHERE id = $2")
+        .bind(&transfer.amount)
+        .bind(transfer.dst_account_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let transaction: Transaction = sqlx::query_as(
+        r#"
+        INSERT INTO transactions(
+            amount, src_account_id, dst_account_id, tx_timestamp
+        ) VALUES ($1, $2, $3, NOW())
+        RETURNING id, amount, currency, status, src_account_id, dst_account_id, tx_timestamp
+    "#,
+    )
+    .bind(&transfer.amount)
+    .bind(transfer.src_account_id)
+    .bind(transfer.dst_account_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // double entry: sum of the entries of a transaction is checked on commit
+    sqlx::query(
+        r#"
+        INSERT INTO ledger_entries(account_id, transaction_id, amount, created_at)
+        VALUES ($1, $3, -$4, $5), ($2, $3, $4, $5)
+    "#,
+    )
+    .bind(transfer.src_account_id)
+    .bind(transfer.dst_account_id)
+    .bind(transaction.id)
+    .bind(&transfer.amount)
+    .bind(transaction.tx_timestamp)
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(key) = idempotency_key {
+        sqlx::query("UPDATE idempotency_keys SET transaction_id = $1 WHERE idempotency_key = $2")
+            .bind(transaction.id)
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(TransferOutcome::Created(transaction))
+}
+
+async fn fetch_transaction(
+    tx: &mut PgTransaction<'_>,
+    id: i64,
+) -> Result<Transaction, PersistError> {
+    Ok(sqlx::query_as(
+        r#"
+        SELECT id, amount, currency, status, src_account_id, dst_account_id, tx_timestamp
+        FROM transactions
+        WHERE id = $1
+    "#,
+    )
+    .bind(id)
+    .fetch_one(&mut **tx)
+    .await?)
+}
+
+/// Accounts whose balance differs from the sum of their ledger entries
+pub async fn reconcile_ledger(db: &PgPool) -> Result<Vec<BalanceMismatch>, PersistError> {
+    Ok(sqlx::query_as(
+        r#"
+        SELECT
+            acc.id AS account_id,
+            acc.balance,
+            COALESCE(SUM(l.amount), 0) AS ledger_balance
+        FROM accounts acc
+            LEFT JOIN ledger_entries l ON l.account_id = acc.id
+        GROUP BY acc.id, acc.balance
+        HAVING acc.balance <> COALESCE(SUM(l.amount), 0)
+        ORDER BY acc.id
+    "#,
+    )
+    .fetch_all(db)
+    .await?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_demo1::{
+        app_config::{DbConfig, SslMode},
+        migrate::MIGRATOR,
+        secret::Secret,
+    };
+    use testcontainers::{
+        core::{IntoContainerPort, WaitFor},
+        runners::AsyncRunner,
+        ContainerAsync, GenericImage, ImageExt,
+    };
+
+    // the container must outlive the pool
+    async fn start_postgres() -> (ContainerAsync<GenericImage>, PgPool) {
+        let container = GenericImage::new("postgres", "17-alpine")
+            .with_wait_for(WaitFor::message_on_stderr(
+                "database system is ready to accept connections",
+            ))
+            .with_exposed_port(5432.tcp())
+            .with_env_var("POSTGRES_PASSWORD", "1111")
+            .start()
+            .await
+            .expect("Postgres started");
+
+        let db = DbConfig {
+            host: container.get_host().await.unwrap().to_string(),
+            port: container.get_host_port_ipv4(5432).await.unwrap(),
+            login: "postgres".into(),
+            password: Some(Secret::new("1111".into())),
+            password_file: None,
+            password_env: None,
+            database: "postgres".into(),
+            // the concurrent transfers of the tests
+            pool_size: 20,
+            acquire_timeout_secs: 30,
+            ssl_mode: SslMode::Disable,
+        };
+        let pool = db.connect().await.unwrap();
+
+        MIGRATOR.run(&pool).await.unwrap();
+        (container, pool)
+    }
+
+    #[test]
+    fn test_parse_cursor() {
+        assert_eq!(
+            parse_cursor::<String>("a:b:1001").unwrap(),
+            ("a:b".into(), 1001)
+        );
+        assert_eq!(
+            parse_cursor::<BigDecimal>("10.50:7").unwrap(),
+            (BigDecimal::from_str("10.50").unwrap(), 7)
+        );
+        assert!(matches!(
+            parse_cursor::<BigDecimal>("abc:7"),
+            Err(PersistError::InvalidCursor)
+        ));
+        assert!(matches!(parse_id("7:"), Err(PersistError::InvalidCursor)));
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+    }
+
+    // a database error with only a SQLSTATE code
+    #[derive(Debug)]
+    struct CodeError(&'static str);
+
+    impl std::fmt::Display for CodeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl std::error::Error for CodeError {}
+
+    impl sqlx::error::DatabaseError for CodeError {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let error = |code| sqlx::Error::Database(Box::new(CodeError(code)));
+        assert!(is_retryable(&error(SERIALIZATION_FAILURE)));
+        assert!(is_retryable(&error(DEADLOCK_DETECTED)));
+        // unique violation
+        assert!(!is_retryable(&error("23505")));
+        assert!(!is_retryable(&sqlx::Error::RowNotFound));
+    }
+
+    #[tokio::test]
+    async fn test_list_accounts_pages() {
+        let (_container, pool) = start_postgres().await;
+        for (name, balance) in [("Ann", 30), ("Bob", 10), ("Anton", 20), ("Al_x", 20)] {
+            create_accounts(&pool, name, BigDecimal::from(balance))
+                .await
+                .unwrap();
+        }
+
+        let mut query = AccountQuery {
+            sort: AccountSort::Balance,
+            order: SortOrder::Desc,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = list_accounts(&pool, &query).await.unwrap();
+        let names: Vec<_> = first.items.iter().map(|a| a.owner_name.as_str()).collect();
+        // equal balances are ordered by id, descending too
+        assert_eq!(names, ["Ann", "Al_x"]);
+
+        query.cursor = first.next_cursor;
+        let second = list_accounts(&pool, &query).await.unwrap();
+        let names: Vec<_> = second.items.iter().map(|a| a.owner_name.as_str()).collect();
+        assert_eq!(names, ["Anton", "Bob"]);
+        assert!(second.next_cursor.is_none());
+
+        let prefixed = AccountQuery {
+            owner_prefix: Some("A".into()),
+            min_balance: Some(BigDecimal::from(25)),
+            ..Default::default()
+        };
+        let names: Vec<_> = list_accounts(&pool, &prefixed)
+            .await
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|a| a.owner_name)
+            .collect();
+        assert_eq!(names, ["Ann"]);
+
+        // `_` is not a wildcard
+        let literal = AccountQuery {
+            owner_prefix: Some("A_".into()),
+            ..Default::default()
+        };
+        assert!(list_accounts(&pool, &literal)
+            .await
+            .unwrap()
+            .items
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_opposite_transfers_conserve_balance() {
+        let (_container, pool) = start_postgres().await;
+
+        let a = create_accounts(&pool, "Stress-A", BigDecimal::from(1000))
+            .await
+            .unwrap();
+        let b = create_accounts(&pool, "Stress-B", BigDecimal::from(1000))
+            .await
+            .unwrap();
+
+        // A->B and B->A interleaved, each pair locks the same two rows
+        let transfers = (0..200).map(|i| {
+            let pool = pool.clone();
+            let (src, dst) = if i % 2 == 0 {
+                (a.id, b.id)
+            } else {
+                (b.id, a.id)
+            };
+            tokio::spawn(async move {
+                let transfer = Transfer {
+                    src_account_id: src,
+                    dst_account_id: dst,
+                    amount: BigDecimal::from(i % 7 + 1),
+                };
+                make_transfer(&pool, &transfer, None).await
+            })
+        });
+        for result in futures::future::join_all(transfers).await {
+            assert!(matches!(result.unwrap(), Ok(TransferOutcome::Created(_))));
+        }
+
+        let total: BigDecimal = sqlx::query_scalar("SELECT SUM(balance) FROM accounts")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(total, BigDecimal::from(2000));
+        assert!(reconcile_ledger(&pool).await.unwrap().is_empty());
+
+        let transactions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM transactions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(transactions, 200);
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::Mutex,
+};
+
+use bigdecimal::BigDecimal;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+
+use super::{
+    page_size, Account, AccountQuery, AccountRepository, BalanceMismatch, ImportReport, ImportRow,
+    Page, PersistError, RowError, Transaction, TransactionQuery, Transfer, TransferOutcome,
+    TransferStatus,
+};
+
+// same as the sequences in migrations/
+const FIRST_ID: i64 = 1000;
+
+/// Keeps everything in memory, so the handlers can be tested without Postgres.
+/// Owner names are compared byte-wise, unlike the collation Postgres sorts them by.
+pub struct InMemoryAccountRepository {
+    state: Mutex<State>,
+}
+
+struct State {
+    accounts: BTreeMap<i64, Account>,
+    transactions: Vec<Transaction>,
+    ledger: Vec<LedgerEntry>,
+    // idempotency key -> (fingerprint of the transfer, id of its transaction)
+    idempotency_keys: HashMap<String, (String, i64)>,
+    next_account_id: i64,
+    next_transaction_id: i64,
+}
+
+struct LedgerEntry {
+    account_id: i64,
+    // `None` for the opening balance
+    transaction_id: Option<i64>,
+    amount: BigDecimal,
+}
+
+impl InMemoryAccountRepository {
+    pub fn new() -> Self {
+        InMemoryAccountRepository {
+            state: Mutex::new(State {
+                accounts: BTreeMap::new(),
+                transactions: Vec::new(),
+                ledger: Vec::new(),
+                idempotency_keys: HashMap::new(),
+                next_account_id: FIRST_ID,
+                next_transaction_id: FIRST_ID,
+            }),
+        }
+    }
+}
+
+impl State {
+    fn insert_account(&mut self, owner_name: &str, initial_balance: BigDecimal) -> Account {
+        let id = self.next_account_id;
+        self.next_account_id += 1;
+        let account = Account {
+            id,
+            owner_name: owner_name.to_string(),
+            balance: initial_balance.clone(),
+        };
+        self.accounts.insert(id, account.clone());
+        self.ledger.push(LedgerEntry {
+            account_id: id,
+            transaction_id: None,
+            amount: initial_balance,
+        });
+        account
+    }
+
+    fn check_owner_free(&self, owner_name: &str, id: Option<i64>) -> Result<(), PersistError> {
+        let taken = self
+            .accounts
+            .values()
+            .any(|acc| acc.owner_name == owner_name && Some(acc.id) != id);
+        if taken {
+            return Err(PersistError::DuplicateOwner(owner_name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl AccountRepository for InMemoryAccountRepository {
+    async fn list_accounts(&self, query: &AccountQuery) -> Result<Page<Account>, PersistError> {
+        let limit = page_size(query.limit);
+        let after = query
+            .cursor
+            .as_deref()
+            .map(|cursor| query.sort.parse_key(cursor))
+            .transpose()?;
+
+        let state = self.state.lock().unwrap();
+        let mut rows: Vec<_> = state
+            .accounts
+            .values()
+            .filter(|acc| {
+                query
+                    .owner_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| acc.owner_name.starts_with(prefix.as_str()))
+            })
+            .filter(|acc| {
+                query
+                    .min_balance
+                    .as_ref()
+                    .is_none_or(|min| acc.balance >= *min)
+            })
+            .filter(|acc| {
+                query
+                    .max_balance
+                    .as_ref()
+                    .is_none_or(|max| acc.balance <= *max)
+            })
+            .map(|acc| (query.sort.key(acc), acc))
+            .filter(|(key, _)| {
+                after
+                    .as_ref()
+                    .is_none_or(|after| query.order.is_after(key, after))
+            })
+            .collect();
+        rows.sort_by(|(a, _), (b, _)| query.order.compare(a, b));
+
+        let rows = rows
+            .into_iter()
+            .take(limit as usize + 1)
+            .map(|(_, acc)| acc.clone())
+            .collect();
+        Ok(Page::new(rows, limit, |acc: &Account| {
+            query.sort.key(acc).to_string()
+        }))
+    }
+
+    async fn fetch_account(&self, id: i64) -> Result<Account, PersistError> {
+        let state = self.state.lock().unwrap();
+        state
+            .accounts
+            .get(&id)
+            .cloned()
+            .ok_or(PersistError::NotFound(id))
+    }
+
+    async fn create_account(
+        &self,
+        owner_name: &str,
+        initial_balance: BigDecimal,
+    ) -> Result<Account, PersistError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_owner_free(owner_name, None)?;
+        Ok(state.insert_account(owner_name, initial_balance))
+    }
+
+    async fn update_account(&self, id: i64, owner_name: &str) -> Result<Account, PersistError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_owner_free(owner_name, Some(id))?;
+
+        let account = state
+            .accounts
+            .get_mut(&id)
+            .ok_or(PersistError::NotFound(id))?;
+        account.owner_name = owner_name.to_string();
+        Ok(account.clone())
+    }
+
+    async fn delete_account(&self, id: i64) -> Result<(), PersistError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.accounts.contains_key(&id) {
+            return Err(PersistError::NotFound(id));
+        }
+        let in_use = state
+            .transactions
+            .iter()
+            .any(|tx| tx.src_account_id == id || tx.dst_account_id == id);
+        if in_use {
+            return Err(PersistError::AccountInUse(id));
+        }
+
+        state.accounts.remove(&id);
+        state
+            .ledger
+            .retain(|entry| entry.account_id != id || entry.transaction_id.is_some());
+        Ok(())
+    }
+
+    async fn list_transactions(
+        &self,
+        query: &TransactionQuery,
+    ) -> Result<Page<Transaction>, PersistError> {
+        let limit = page_size(query.limit);
+        let after = query
+            .cursor
+            .as_deref()
+            .map(|cursor| query.sort.parse_key(cursor))
+            .transpose()?;
+
+        let state = self.state.lock().unwrap();
+        let mut rows: Vec<_> = state
+            .transactions
+            .iter()
+            .filter(|tx| {
+                query.account_id.is_none_or(|account_id| {
+                    tx.src_account_id == account_id || tx.dst_account_id == account_id
+                })
+            })
+            .filter(|tx| query.from.is_none_or(|from| tx.tx_timestamp >= from))
+            .filter(|tx| query.to.is_none_or(|to| tx.tx_timestamp < to))
+            .map(|tx| (query.sort.key(tx), tx))
+            .filter(|(key, _)| {
+                after
+                    .as_ref()
+                    .is_none_or(|after| query.order.is_after(key, after))
+            })
+            .collect();
+        rows.sort_by(|(a, _), (b, _)| query.order.compare(a, b));
+
+        let rows = rows
+            .into_iter()
+            .take(limit as usize + 1)
+            .map(|(_, tx)| tx.clone())
+            .collect();
+        Ok(Page::new(rows, limit, |tx: &Transaction| {
+            query.sort.key(tx).to_string()
+        }))
+    }
+
+    async fn make_transfer(
+        &self,
+        transfer: &Transfer,
+        idempotency_key: Option<&str>,
+    ) -> Result<TransferOutcome, PersistError> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(key) = idempotency_key {
+            if let Some((fingerprint, transaction_id)) = state.idempotency_keys.get(key) {
+                if *fingerprint != transfer.fingerprint() {
+                    return Err(PersistError::IdempotencyKeyReused(key.to_string()));
+                }
+                let transaction = state
+                    .transactions
+                    .iter()
+                    .find(|tx| tx.id == *transaction_id)
+                    .cloned()
+                    .expect("idempotency key points to an existing transaction");
+                return Ok(TransferOutcome::Replayed(transaction));
+            }
+        }
+
+        let src_balance = state
+            .accounts
+            .get(&transfer.src_account_id)
+            .map(|acc| &acc.balance)
+            .ok_or(PersistError::NotFound(transfer.src_account_id))?;
+        if !state.accounts.contains_key(&transfer.dst_account_id) {
+            return Err(PersistError::NotFound(transfer.dst_account_id));
+        }
+        if *src_balance < transfer.amount {
+            return Err(PersistError::InsufficientFunds(transfer.src_account_id));
+        }
+
+        for (account_id, amount) in [
+            (transfer.src_account_id, -transfer.amount.clone()),
+            (transfer.dst_account_id, transfer.amount.clone()),
+        ] {
+            let account = state.accounts.get_mut(&account_id).unwrap();
+            account.balance += &amount;
+            let transaction_id = state.next_transaction_id;
+            state.ledger.push(LedgerEntry {
+                account_id,
+                transaction_id: Some(transaction_id),
+                amount,
+            });
+        }
+
+        let transaction = Transaction {
+            id: state.next_transaction_id,
+            amount: transfer.amount.clone(),
+            currency: "USD".to_string(),
+            status: TransferStatus::Completed,
+            src_account_id: transfer.src_account_id,
+            dst_account_id: transfer.dst_account_id,
+            tx_timestamp: chrono::Utc::now().naive_utc(),
+        };
+        state.next_transaction_id += 1;
+        state.transactions.push(transaction.clone());
+        if let Some(key) = idempotency_key {
+            state
+                .idempotency_keys
+                .insert(key.to_string(), (transfer.fingerprint(), transaction.id));
+        }
+        Ok(TransferOutcome::Created(transaction))
+    }
+
+    async fn reconcile_ledger(&self) -> Result<Vec<BalanceMismatch>, PersistError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .accounts
+            .values()
+            .filter_map(|acc| {
+                let ledger_balance: BigDecimal = state
+                    .ledger
+                    .iter()
+                    .filter(|entry| entry.account_id == acc.id)
+                    .map(|entry| &entry.amount)
+                    .sum();
+                (ledger_balance != acc.balance).then(|| BalanceMismatch {
+                    account_id: acc.id,
+                    balance: acc.balance.clone(),
+                    ledger_balance,
+                })
+            })
+            .collect())
+    }
+
+    async fn import_accounts(
+        &self,
+        rows: BoxStream<'_, io::Result<ImportRow>>,
+    ) -> Result<ImportReport, PersistError> {
+        // nothing is stored until the whole input is read, like a rolled back transaction
+        let rows: Vec<ImportRow> = rows.try_collect().await?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut report = ImportReport::default();
+        for (line, account) in rows {
+            match state.check_owner_free(&account.owner_name, None) {
+                Ok(()) => {
+                    state.insert_account(&account.owner_name, account.balance);
+                    report.imported += 1;
+                }
+                Err(e) => report.errors.push(RowError {
+                    line,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        Ok(report)
+    }
+
+    fn export_accounts(&self) -> BoxStream<'static, Result<Account, PersistError>> {
+        let accounts: Vec<Account> = self
+            .state
+            .lock()
+            .unwrap()
+            .accounts
+            .values()
+            .cloned()
+            .collect();
+        stream::iter(accounts).map(Ok).boxed()
+    }
+}
@@ -0,0 +1,597 @@
+use std::{io, sync::Arc};
+
+use crate::{
+    bulk::{BulkFormat, RowParser},
+    persist::{
+        Account, AccountQuery, AccountRepository, BalanceMismatch, ImportReport,
+        InMemoryAccountRepository, Page, PersistError, PgAccountRepository, RowError, Transaction,
+        TransactionQuery, Transfer, TransferOutcome,
+    },
+};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use rust_demo1::{app_config::DbConfig, migrate};
+use serde::Deserialize;
+use sqlx::types::BigDecimal;
+use tokio_util::{
+    codec::{FramedRead, LinesCodec, LinesCodecError},
+    io::StreamReader,
+};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+// longer lines of an import are reported as row errors
+const MAX_IMPORT_LINE_LENGTH: usize = 4096;
+
+#[derive(Clone)]
+struct AppState {
+    accounts: Arc<dyn AccountRepository>,
+}
+
+pub struct ServerArgs {
+    /// Apply pending migrations before serving
+    pub migrate_on_start: bool,
+    /// Keep accounts in memory instead of Postgres, nothing survives a restart
+    pub in_memory: bool,
+}
+
+pub async fn run_server(args: ServerArgs) {
+    let accounts: Arc<dyn AccountRepository> = if args.in_memory {
+        tracing::info!("accounts are kept in memory");
+        Arc::new(InMemoryAccountRepository::new())
+    } else {
+        let db = DbConfig::load().expect("Cannot load the db config");
+        let pool = db.connect().await.unwrap();
+
+        if args.migrate_on_start {
+            migrate::up(&pool).await.expect("Cannot apply migrations");
+            tracing::info!("migrations applied");
+        }
+        Arc::new(PgAccountRepository::new(pool))
+    };
+
+    let state = AppState { accounts };
+    let app = app(state);
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+
+    axum::serve(listener, app).await.unwrap();
+}
+
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/accounts", get(list_accounts))
+        .route("/accounts", post(create_new_account))
+        .route("/accounts/import", post(import_accounts))
+        .route("/accounts/export", get(export_accounts))
+        .route("/accounts/{id}", get(get_account))
+        .route("/accounts/{id}", put(update_account))
+        .route("/accounts/{id}", delete(delete_account))
+        .route(
+            "/accounts/{id}/transactions",
+            get(list_account_transactions),
+        )
+        .route("/transactions", get(list_transactions))
+        .route("/transfers", post(create_transfer))
+        .route("/ledger/reconciliation", get(reconcile_ledger))
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct NewAcc {
+    owner_name: String,
+    init_balance: BigDecimal,
+}
+
+#[derive(Deserialize)]
+struct AccUpdate {
+    owner_name: String,
+}
+
+#[derive(Deserialize)]
+struct NewTransfer {
+    src_account_id: i64,
+    dst_account_id: i64,
+    amount: BigDecimal,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: BulkFormat,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ApiError {
+    #[error("{0}")]
+    BadRequest(&'static str),
+    #[error("{0}")]
+    UnsupportedMediaType(&'static str),
+    #[error(transparent)]
+    Persist(#[from] PersistError),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            ApiError::UnsupportedMediaType(msg) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response()
+            }
+            ApiError::Persist(e) => e.into_response(),
+        }
+    }
+}
+
+impl IntoResponse for PersistError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            PersistError::NotFound(_) => StatusCode::NOT_FOUND,
+            PersistError::InvalidCursor | PersistError::ImportRead(_) => StatusCode::BAD_REQUEST,
+            PersistError::DuplicateOwner(_) | PersistError::AccountInUse(_) => StatusCode::CONFLICT,
+            PersistError::InsufficientFunds(_) | PersistError::IdempotencyKeyReused(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            PersistError::Db(e) => {
+                // details stay in the log, clients don't need to see SQL errors
+                tracing::error!(error = %e, "database error");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+                    .into_response();
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+async fn list_accounts(
+    State(state): State<AppState>,
+    Query(query): Query<AccountQuery>,
+) -> Result<Json<Page<Account>>, ApiError> {
+    Ok(Json(state.accounts.list_accounts(&query).await?))
+}
+
+async fn create_new_account(
+    State(state): State<AppState>,
+    Json(acc): Json<NewAcc>,
+) -> Result<Json<Account>, ApiError> {
+    if acc.init_balance < 0 {
+        return Err(ApiError::BadRequest("Initial balance cannot be negative"));
+    }
+    let account = state
+        .accounts
+        .create_account(&acc.owner_name, acc.init_balance)
+        .await?;
+    Ok(Json(account))
+}
+
+/// Reads the body line by line, valid rows are imported and the invalid ones reported
+async fn import_accounts(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ImportReport>, ApiError> {
+    let format = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(BulkFormat::from_content_type)
+        .ok_or(ApiError::UnsupportedMediaType(
+            "Expected text/csv or application/x-ndjson",
+        ))?;
+
+    let body = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let mut lines = FramedRead::new(
+        body,
+        LinesCodec::new_with_max_length(MAX_IMPORT_LINE_LENGTH),
+    );
+    let (parser, first_line) = match format {
+        BulkFormat::Csv => {
+            let header = lines.next().await.transpose().ok().flatten();
+            let parser = header
+                .as_deref()
+                .and_then(RowParser::csv)
+                .ok_or(ApiError::BadRequest(
+                    "CSV must start with an owner_name,balance header",
+                ))?;
+            (parser, 2)
+        }
+        BulkFormat::JsonLines => (RowParser::JsonLines, 1),
+    };
+
+    let mut errors = Vec::new();
+    let row_errors = &mut errors;
+    let rows = async_stream::stream! {
+        let mut n = first_line;
+        let mut after_long_line = false;
+        loop {
+            let line = match lines.next().await {
+                Some(line) => line,
+                // the lines end once after a too long line, then go on past its end
+                None if after_long_line => {
+                    after_long_line = false;
+                    continue;
+                }
+                None => break,
+            };
+            after_long_line = false;
+            match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => match parser.parse(&line) {
+                    Ok(account) => yield Ok((n, account)),
+                    Err(error) => row_errors.push(RowError { line: n, error }),
+                },
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    after_long_line = true;
+                    row_errors.push(RowError {
+                        line: n,
+                        error: format!("Line is longer than {MAX_IMPORT_LINE_LENGTH} bytes"),
+                    });
+                }
+                Err(LinesCodecError::Io(e)) => yield Err(e),
+            }
+            n += 1;
+        }
+    };
+    let mut report = state.accounts.import_accounts(Box::pin(rows)).await?;
+
+    report.errors.append(&mut errors);
+    report.errors.sort_by_key(|e| e.line);
+    Ok(Json(report))
+}
+
+async fn export_accounts(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    let format = params.format;
+    let header = stream::iter(format.header().map(Ok));
+    let rows = state.accounts.export_accounts().map(move |account| {
+        // the status is already sent, the client only sees a cut off body
+        account
+            .map(|account| format.encode(&account))
+            .inspect_err(|e| tracing::error!(error = %e, "account export failed"))
+    });
+
+    (
+        [
+            (CONTENT_TYPE, format.content_type().to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", format.file_name()),
+            ),
+        ],
+        Body::from_stream(header.chain(rows)),
+    )
+        .into_response()
+}
+
+async fn get_account(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Account>, ApiError> {
+    Ok(Json(state.accounts.fetch_account(id).await?))
+}
+
+async fn update_account(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(upd): Json<AccUpdate>,
+) -> Result<Json<Account>, ApiError> {
+    Ok(Json(
+        state.accounts.update_account(id, &upd.owner_name).await?,
+    ))
+}
+
+async fn delete_account(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    state.accounts.delete_account(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_account_transactions(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(mut query): Query<TransactionQuery>,
+) -> Result<Json<Page<Transaction>>, ApiError> {
+    state.accounts.fetch_account(id).await?;
+    query.account_id = Some(id);
+    Ok(Json(state.accounts.list_transactions(&query).await?))
+}
+
+async fn list_transactions(
+    State(state): State<AppState>,
+    Query(query): Query<TransactionQuery>,
+) -> Result<Json<Page<Transaction>>, ApiError> {
+    Ok(Json(state.accounts.list_transactions(&query).await?))
+}
+
+async fn create_transfer(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<NewTransfer>,
+) -> Result<Response, ApiError> {
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= 255 => Some(key),
+            _ => return Err(ApiError::BadRequest("Invalid Idempotency-Key header")),
+        },
+        None => None,
+    };
+    if req.amount <= 0 {
+        return Err(ApiError::BadRequest("Amount must be positive"));
+    }
+    if req.src_account_id == req.dst_account_id {
+        return Err(ApiError::BadRequest("Cannot transfer to the same account"));
+    }
+
+    let transfer = Transfer {
+        src_account_id: req.src_account_id,
+        dst_account_id: req.dst_account_id,
+        amount: req.amount,
+    };
+    match state
+        .accounts
+        .make_transfer(&transfer, idempotency_key)
+        .await?
+    {
+        TransferOutcome::Created(transaction) => {
+            Ok((StatusCode::CREATED, Json(transaction)).into_response())
+        }
+        TransferOutcome::Replayed(transaction) => Ok((
+            StatusCode::CREATED,
+            [("idempotent-replayed", "true")],
+            Json(transaction),
+        )
+            .into_response()),
+    }
+}
+
+async fn reconcile_ledger(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<BalanceMismatch>>, ApiError> {
+    Ok(Json(state.accounts.reconcile_ledger().await?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum_test::TestServer;
+    use serde_json::{json, Value};
+
+    fn test_server() -> TestServer {
+        let state = AppState {
+            accounts: Arc::new(InMemoryAccountRepository::new()),
+        };
+        TestServer::new(app(state)).unwrap()
+    }
+
+    async fn create(server: &TestServer, owner_name: &str, init_balance: i64) -> i64 {
+        let response = server
+            .post("/accounts")
+            .json(&json!({ "owner_name": owner_name, "init_balance": init_balance }))
+            .await;
+        response.assert_status_ok();
+        response.json::<Value>()["id"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_account_crud() {
+        let server = test_server();
+        let id = create(&server, "Acc-1", 100).await;
+
+        server
+            .post("/accounts")
+            .json(&json!({ "owner_name": "Acc-1", "init_balance": 5 }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        server
+            .post("/accounts")
+            .json(&json!({ "owner_name": "Acc-2", "init_balance": -5 }))
+            .await
+            .assert_status_bad_request();
+
+        server
+            .put(&format!("/accounts/{id}"))
+            .json(&json!({ "owner_name": "Acc-1-renamed" }))
+            .await
+            .assert_json(&json!({ "id": id, "owner_name": "Acc-1-renamed", "balance": "100" }));
+
+        server
+            .delete(&format!("/accounts/{id}"))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .get(&format!("/accounts/{id}"))
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_list_accounts_pages() {
+        let server = test_server();
+        for (name, balance) in [("Ann", 30), ("Bob", 10), ("Anton", 20)] {
+            create(&server, name, balance).await;
+        }
+
+        let first = server
+            .get("/accounts?sort=balance&order=desc&limit=2")
+            .await
+            .json::<Value>();
+        assert_eq!(first["items"][0]["owner_name"], "Ann");
+        assert_eq!(first["items"][1]["owner_name"], "Anton");
+
+        let second = server
+            .get("/accounts")
+            .add_query_params(json!({
+                "sort": "balance",
+                "order": "desc",
+                "limit": 2,
+                "cursor": first["next_cursor"],
+            }))
+            .await
+            .json::<Value>();
+        assert_eq!(second["items"][0]["owner_name"], "Bob");
+        assert_eq!(second["next_cursor"], Value::Null);
+
+        server
+            .get("/accounts?owner_prefix=An&max_balance=25")
+            .await
+            .assert_json_contains(&json!({ "items": [{ "owner_name": "Anton" }] }));
+        server
+            .get("/accounts?sort=password")
+            .await
+            .assert_status_bad_request();
+        server
+            .get("/accounts?sort=balance&cursor=abc")
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn test_transfers() {
+        let server = test_server();
+        let a = create(&server, "A", 100).await;
+        let b = create(&server, "B", 0).await;
+
+        let transfer = json!({ "src_account_id": a, "dst_account_id": b, "amount": 40 });
+        let first = server
+            .post("/transfers")
+            .add_header(IDEMPOTENCY_KEY_HEADER, "key-1")
+            .json(&transfer)
+            .await;
+        first.assert_status(StatusCode::CREATED);
+        let replayed = server
+            .post("/transfers")
+            .add_header(IDEMPOTENCY_KEY_HEADER, "key-1")
+            .json(&transfer)
+            .await;
+        replayed.assert_status(StatusCode::CREATED);
+        replayed.assert_header("idempotent-replayed", "true");
+        assert_eq!(replayed.json::<Value>(), first.json::<Value>());
+
+        server
+            .post("/transfers")
+            .add_header(IDEMPOTENCY_KEY_HEADER, "key-1")
+            .json(&json!({ "src_account_id": a, "dst_account_id": b, "amount": 1 }))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .post("/transfers")
+            .json(&json!({ "src_account_id": a, "dst_account_id": b, "amount": 61 }))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .post("/transfers")
+            .json(&json!({ "src_account_id": a, "dst_account_id": 1, "amount": 1 }))
+            .await
+            .assert_status_not_found();
+
+        server
+            .get(&format!("/accounts/{b}"))
+            .await
+            .assert_json_contains(&json!({ "balance": "40" }));
+        server
+            .get(&format!("/accounts/{b}/transactions"))
+            .await
+            .assert_json_contains(&json!({ "items": [{ "amount": "40", "src_account_id": a }] }));
+        server
+            .delete(&format!("/accounts/{a}"))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        server
+            .get("/ledger/reconciliation")
+            .await
+            .assert_json(&json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_import_export() {
+        let server = test_server();
+        create(&server, "Existing", 1).await;
+
+        let csv = "owner_name,balance\nA,10.50\n\n\"Doe, John\",5\nExisting,1\nB,-1\nA,2\n";
+        server
+            .post("/accounts/import")
+            .content_type("text/csv")
+            .bytes(csv.into())
+            .await
+            .assert_json(&json!({
+                "imported": 2,
+                "errors": [
+                    { "line": 5, "error": "Account of 'Existing' already exists" },
+                    { "line": 6, "error": "Balance cannot be negative" },
+                    { "line": 7, "error": "Account of 'A' already exists" },
+                ]
+            }));
+
+        let ndjson = "{\"owner_name\": \"C\", \"balance\": 3}\n{\"owner_name\": \"D\"}\n";
+        server
+            .post("/accounts/import")
+            .content_type("application/x-ndjson")
+            .bytes(ndjson.into())
+            .await
+            .assert_json_contains(&json!({ "imported": 1, "errors": [{ "line": 2 }] }));
+
+        server
+            .post("/accounts/import")
+            .content_type("application/json")
+            .bytes("[]".into())
+            .await
+            .assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        server
+            .post("/accounts/import")
+            .content_type("text/csv")
+            .bytes("A,1\n".into())
+            .await
+            .assert_status_bad_request();
+
+        let export = server.get("/accounts/export").await;
+        export.assert_header("content-type", "text/csv");
+        export.assert_text(
+            "id,owner_name,balance\n1000,Existing,1\n1001,A,10.5\n1002,\"Doe, John\",5\n1003,C,3\n",
+        );
+        server
+            .get("/accounts/export?format=jsonl")
+            .await
+            .assert_text(concat!(
+                "{\"id\":1000,\"owner_name\":\"Existing\",\"balance\":\"1\"}\n",
+                "{\"id\":1001,\"owner_name\":\"A\",\"balance\":\"10.5\"}\n",
+                "{\"id\":1002,\"owner_name\":\"Doe, John\",\"balance\":\"5\"}\n",
+                "{\"id\":1003,\"owner_name\":\"C\",\"balance\":\"3\"}\n",
+            ));
+    }
+
+    #[tokio::test]
+    async fn test_import_long_line() {
+        let server = test_server();
+
+        let long_name = "x".repeat(MAX_IMPORT_LINE_LENGTH);
+        let csv = format!("owner_name,balance\nA,1\n{long_name},2\nB,3\nC,4\n");
+        server
+            .post("/accounts/import")
+            .content_type("text/csv")
+            .bytes(csv.into())
+            .await
+            .assert_json(&json!({
+                "imported": 3,
+                "errors": [
+                    { "line": 3, "error": "Line is longer than 4096 bytes" },
+                ]
+            }));
+        server
+            .get("/accounts/export")
+            .await
+            .assert_text("id,owner_name,balance\n1000,A,1\n1001,B,3\n1002,C,4\n");
+    }
+}
@@ -0,0 +1,15 @@
+mod server;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    let args: Vec<String> = std::env::args().collect();
+    server::run_server(server::ServerArgs {
+        migrate_on_start: args.iter().any(|arg| arg == "--migrate-on-start"),
+        in_memory: args.iter().any(|arg| arg == "--in-memory"),
+    })
+    .await
+}
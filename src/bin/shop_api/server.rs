@@ -0,0 +1,571 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use rust_demo1::{
+    app_config::DbConfig,
+    migrate,
+    shop::{
+        spawn_expiry_sweeper, Address, Fulfillment, InMemoryOrderLog, InMemoryReservationService,
+        InMemorySagaLog, InMemoryShipmentService, Order, OrderItem, PgOrderLog,
+        PgReservationService, PgSagaLog, PgShipmentService, Purchase, PurchaseError,
+        PurchaseService, Reservation, ReservationService, ReserveError, SagaPurchaseService,
+        ShipmentError,
+    },
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_swagger_ui::SwaggerUi;
+
+// how often the held reservations past their TTL are given back to the stock
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(OpenApi)]
+#[openapi(info(description = "Reservations and purchases of products"))]
+struct ShopApiDoc;
+
+#[derive(Clone)]
+struct AppState {
+    reservations: Arc<dyn ReservationService>,
+    purchases: Arc<dyn PurchaseService>,
+}
+
+pub struct ServerArgs {
+    /// Apply pending migrations before serving
+    pub migrate_on_start: bool,
+    /// Keep the stock in memory instead of Postgres, nothing survives a restart
+    pub in_memory: bool,
+}
+
+pub async fn run_server(args: ServerArgs) {
+    let state = if args.in_memory {
+        tracing::info!("the stock is kept in memory, product 111 has 50 items");
+        let reservations = Arc::new(InMemoryReservationService::new(HashMap::from([(111, 50)])));
+        let purchases = SagaPurchaseService::new(
+            reservations.clone(),
+            Arc::new(InMemoryShipmentService::new()),
+            Arc::new(InMemorySagaLog::new()),
+            Arc::new(InMemoryOrderLog::new()),
+        );
+        AppState {
+            reservations,
+            purchases: Arc::new(purchases),
+        }
+    } else {
+        let db = DbConfig::load().expect("Cannot load the db config");
+        let pool = db.connect().await.unwrap();
+
+        if args.migrate_on_start {
+            migrate::up(&pool).await.expect("Cannot apply migrations");
+            tracing::info!("migrations applied");
+        }
+        let reservations = Arc::new(PgReservationService::new(pool.clone()));
+        let purchases = SagaPurchaseService::new(
+            reservations.clone(),
+            Arc::new(PgShipmentService::new(pool.clone())),
+            Arc::new(PgSagaLog::new(pool.clone())),
+            Arc::new(PgOrderLog::new(pool)),
+        );
+        let recovered = purchases
+            .recover()
+            .await
+            .expect("Cannot recover the purchases");
+        tracing::info!("{} interrupted purchases recovered", recovered.len());
+        let recovered = purchases
+            .recover_orders()
+            .await
+            .expect("Cannot recover the orders");
+        tracing::info!("{} interrupted orders compensated", recovered.len());
+        AppState {
+            reservations,
+            purchases: Arc::new(purchases),
+        }
+    };
+    let _sweeper = spawn_expiry_sweeper(state.reservations.clone(), SWEEP_INTERVAL);
+
+    // Swagger UI: http://localhost:8080/swagger-ui/
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(state)).await.unwrap();
+}
+
+fn app(state: AppState) -> Router {
+    let (router, api) = OpenApiRouter::with_openapi(ShopApiDoc::openapi())
+        .routes(routes!(create_purchase))
+        .routes(routes!(get_purchase))
+        .routes(routes!(create_order))
+        .routes(routes!(get_order))
+        .routes(routes!(get_stock))
+        .routes(routes!(create_reservation))
+        .routes(routes!(release_reservation))
+        .with_state(state)
+        .split_for_parts();
+
+    router.merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", api))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct NewPurchase {
+    product_id: i64,
+    quantity: i64,
+    address: AddressBody,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct NewOrder {
+    items: Vec<OrderItem>,
+    address: AddressBody,
+    #[serde(default)]
+    fulfillment: Fulfillment,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AddressBody {
+    street: String,
+    city: String,
+    postal_code: String,
+    /// ISO 3166 alpha-2 code, e.g. `MD`
+    country: String,
+}
+
+impl AddressBody {
+    fn to_address(&self) -> Result<Address, ShipmentError> {
+        Address::new(&self.street, &self.city, &self.postal_code, &self.country)
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct NewReservation {
+    product_id: i64,
+    quantity: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Stock {
+    product_id: i64,
+    available: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ApiError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error(transparent)]
+    Reserve(#[from] ReserveError),
+    #[error(transparent)]
+    Shipment(#[from] ShipmentError),
+    #[error(transparent)]
+    Purchase(PurchaseError),
+}
+
+impl From<PurchaseError> for ApiError {
+    fn from(e: PurchaseError) -> Self {
+        // the nested errors get the statuses of their own
+        match e {
+            PurchaseError::ReservationFailed(e) => ApiError::Reserve(e),
+            PurchaseError::ShippingFailed(e) => ApiError::Shipment(e),
+            e => ApiError::Purchase(e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ApiError::NotFound(_)
+            | ApiError::Reserve(ReserveError::NoSuchProduct { .. })
+            | ApiError::Reserve(ReserveError::NoSuchReservation { .. }) => StatusCode::NOT_FOUND,
+            ApiError::Reserve(ReserveError::InvalidQuantity(_))
+            | ApiError::Purchase(PurchaseError::EmptyOrder) => StatusCode::BAD_REQUEST,
+            ApiError::Reserve(ReserveError::NotEnough { .. })
+            | ApiError::Reserve(ReserveError::NotHeld { .. })
+            | ApiError::Shipment(ShipmentError::AlreadyShipped { .. }) => StatusCode::CONFLICT,
+            ApiError::Shipment(ShipmentError::InvalidAddress { .. }) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Purchase(PurchaseError::Interrupted(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Reserve(ReserveError::Db(e))
+            | ApiError::Shipment(ShipmentError::Db(e))
+            | ApiError::Purchase(PurchaseError::Db(e)) => {
+                // details stay in the log, clients don't need to see SQL errors
+                tracing::error!(error = %e, "database error");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+                    .into_response();
+            }
+            // shipments are grouped by warehouse before they're scheduled, only scheduled
+            // ones are canceled, and the nested errors are unwrapped by `From<PurchaseError>`
+            ApiError::Shipment(ShipmentError::NothingToShip)
+            | ApiError::Shipment(ShipmentError::MixedWarehouses { .. })
+            | ApiError::Shipment(ShipmentError::NoSuchShipment { .. })
+            | ApiError::Purchase(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Reserves the stock and schedules the shipment, the reservation is released if the
+/// shipment fails
+#[utoipa::path(
+    post,
+    path = "/purchases",
+    request_body = NewPurchase,
+    responses(
+        (status = 201, description = "Purchase completed", body = Purchase),
+        (status = 400, description = "Quantity is not positive", body = String),
+        (status = 404, description = "No such product", body = String),
+        (status = 409, description = "Not enough stock", body = String),
+        (status = 422, description = "Invalid address, the field is named", body = String),
+        (status = 503, description = "Purchase interrupted", body = String),
+    ),
+    summary = "Purchase a product",
+)]
+async fn create_purchase(
+    State(state): State<AppState>,
+    Json(req): Json<NewPurchase>,
+) -> Result<(StatusCode, Json<Purchase>), ApiError> {
+    let address = req.address.to_address()?;
+    let purchase = state
+        .purchases
+        .purchase(req.product_id, req.quantity, &address)
+        .await?;
+    Ok((StatusCode::CREATED, Json(purchase)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/purchases/{id}",
+    params(("id" = i64, Path, description = "Purchase ID")),
+    responses(
+        (status = 200, description = "Completed purchase", body = Purchase),
+        (status = 404, description = "No such completed purchase", body = String),
+    ),
+    summary = "Get a purchase",
+)]
+async fn get_purchase(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Purchase>, ApiError> {
+    match state.purchases.find_purchase(id).await? {
+        Some(purchase) => Ok(Json(purchase)),
+        None => Err(ApiError::NotFound(format!("No purchase with ID {id}"))),
+    }
+}
+
+/// Reserves the items and schedules one shipment per warehouse. All or nothing by
+/// default, with the `partial` fulfillment the missing stock is backordered.
+#[utoipa::path(
+    post,
+    path = "/orders",
+    request_body = NewOrder,
+    responses(
+        (status = 201, description = "Order placed", body = Order),
+        (status = 400, description = "No items, or a quantity is not positive", body = String),
+        (status = 404, description = "No such product", body = String),
+        (status = 409, description = "Not enough stock for all or nothing", body = String),
+        (status = 422, description = "Invalid address, the field is named", body = String),
+    ),
+    summary = "Place an order of several products",
+)]
+async fn create_order(
+    State(state): State<AppState>,
+    Json(req): Json<NewOrder>,
+) -> Result<(StatusCode, Json<Order>), ApiError> {
+    let address = req.address.to_address()?;
+    let order = state
+        .purchases
+        .place_order(&req.items, &address, req.fulfillment)
+        .await?;
+    Ok((StatusCode::CREATED, Json(order)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/orders/{id}",
+    params(("id" = i64, Path, description = "Order ID")),
+    responses(
+        (status = 200, description = "Order, placed or not", body = Order),
+        (status = 404, description = "No such order", body = String),
+    ),
+    summary = "Get an order",
+)]
+async fn get_order(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Order>, ApiError> {
+    match state.purchases.find_order(id).await? {
+        Some(order) => Ok(Json(order)),
+        None => Err(ApiError::NotFound(format!("No order with ID {id}"))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/products/{id}/stock",
+    params(("id" = i64, Path, description = "Product ID")),
+    responses(
+        (status = 200, description = "Stock not reserved", body = Stock),
+        (status = 404, description = "No such product", body = String),
+    ),
+    summary = "Get the available stock of a product",
+)]
+async fn get_stock(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Stock>, ApiError> {
+    let available = state.reservations.available(id).await?;
+    Ok(Json(Stock {
+        product_id: id,
+        available,
+    }))
+}
+
+/// The reservation holds the stock until it's released or its TTL is over
+#[utoipa::path(
+    post,
+    path = "/reservations",
+    request_body = NewReservation,
+    responses(
+        (status = 201, description = "Held reservation", body = Reservation),
+        (status = 400, description = "Quantity is not positive", body = String),
+        (status = 404, description = "No such product", body = String),
+        (status = 409, description = "Not enough stock", body = String),
+    ),
+    summary = "Reserve stock",
+)]
+async fn create_reservation(
+    State(state): State<AppState>,
+    Json(req): Json<NewReservation>,
+) -> Result<(StatusCode, Json<Reservation>), ApiError> {
+    let reservation = state
+        .reservations
+        .reserve(req.product_id, req.quantity)
+        .await?;
+    Ok((StatusCode::CREATED, Json(reservation)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/reservations/{id}",
+    params(("id" = i64, Path, description = "Reservation ID")),
+    responses(
+        (status = 204, description = "Stock given back, or it already was"),
+        (status = 404, description = "No such reservation", body = String),
+        (status = 409, description = "Reservation confirmed, it's sold", body = String),
+    ),
+    summary = "Release a reservation",
+)]
+async fn release_reservation(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    // the sold stock is given back only by compensating the purchase or the order
+    state.reservations.release_held(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum_test::TestServer;
+    use serde_json::{json, Value};
+
+    fn test_server() -> TestServer {
+        let reservations = Arc::new(
+            InMemoryReservationService::new(HashMap::from([(111, 50), (222, 5)]))
+                .with_warehouse(222, 1001),
+        );
+        let purchases = SagaPurchaseService::new(
+            reservations.clone(),
+            Arc::new(InMemoryShipmentService::new()),
+            Arc::new(InMemorySagaLog::new()),
+            Arc::new(InMemoryOrderLog::new()),
+        );
+        TestServer::new(app(AppState {
+            reservations,
+            purchases: Arc::new(purchases),
+        }))
+        .unwrap()
+    }
+
+    fn purchase(product_id: i64, quantity: i64, postal_code: &str) -> Value {
+        json!({
+            "product_id": product_id,
+            "quantity": quantity,
+            "address": {
+                "street": "Stefan cel Mare 1",
+                "city": "Chisinau",
+                "postal_code": postal_code,
+                "country": "MD"
+            }
+        })
+    }
+
+    async fn available(server: &TestServer) -> i64 {
+        let response = server.get("/products/111/stock").await;
+        response.assert_status_ok();
+        response.json::<Value>()["available"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_purchase() {
+        let server = test_server();
+
+        let response = server
+            .post("/purchases")
+            .json(&purchase(111, 10, "MD-2001"))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let id = response.json::<Value>()["id"].as_i64().unwrap();
+        server
+            .get(&format!("/purchases/{id}"))
+            .await
+            .assert_json(&response.json::<Value>());
+        assert_eq!(available(&server).await, 40);
+
+        let response = server
+            .post("/purchases")
+            .json(&purchase(111, 10, "2001-MD"))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(response.text().starts_with("Invalid postal code"));
+
+        let statuses = [
+            (purchase(112, 1, "MD-2001"), StatusCode::NOT_FOUND),
+            (purchase(111, 0, "MD-2001"), StatusCode::BAD_REQUEST),
+            (purchase(111, 41, "MD-2001"), StatusCode::CONFLICT),
+        ];
+        for (body, status) in statuses {
+            server
+                .post("/purchases")
+                .json(&body)
+                .await
+                .assert_status(status);
+        }
+        assert_eq!(available(&server).await, 40);
+        server
+            .get("/purchases/1001")
+            .await
+            .assert_status_not_found();
+        server
+            .get("/products/112/stock")
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_order() {
+        let server = test_server();
+        let order = |fulfillment: &str| {
+            json!({
+                "items": [
+                    { "product_id": 111, "quantity": 10 },
+                    { "product_id": 222, "quantity": 8 }
+                ],
+                "address": purchase(111, 1, "MD-2001")["address"],
+                "fulfillment": fulfillment
+            })
+        };
+
+        server
+            .post("/orders")
+            .json(&order("all_or_nothing"))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        assert_eq!(available(&server).await, 50);
+        // the failed order is logged too
+        server
+            .get("/orders/1000")
+            .await
+            .assert_json_contains(&json!({ "status": "failed", "shipments": [] }));
+
+        let response = server.post("/orders").json(&order("partial")).await;
+        response.assert_status(StatusCode::CREATED);
+        response.assert_json_contains(&json!({
+            "status": "placed",
+            "lines": [
+                { "product_id": 111, "reserved": 10, "backordered": 0 },
+                { "product_id": 222, "reserved": 5, "backordered": 3 }
+            ],
+            "shipments": [{ "warehouse_id": 1000 }, { "warehouse_id": 1001 }]
+        }));
+        assert_eq!(available(&server).await, 40);
+        let id = response.json::<Value>()["id"].as_i64().unwrap();
+        server
+            .get(&format!("/orders/{id}"))
+            .await
+            .assert_json(&response.json::<Value>());
+        server.get("/orders/1002").await.assert_status_not_found();
+
+        server
+            .post("/orders")
+            .json(&json!({ "items": [], "address": order("partial")["address"] }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_reserve_and_release() {
+        let server = test_server();
+
+        let response = server
+            .post("/reservations")
+            .json(&json!({ "product_id": 111, "quantity": 5 }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        response.assert_json_contains(&json!({ "product_id": 111, "status": "held" }));
+        let id = response.json::<Value>()["id"].as_i64().unwrap();
+        assert_eq!(available(&server).await, 45);
+
+        for _ in 0..2 {
+            server
+                .delete(&format!("/reservations/{id}"))
+                .await
+                .assert_status(StatusCode::NO_CONTENT);
+        }
+        assert_eq!(available(&server).await, 50);
+        server
+            .delete("/reservations/1")
+            .await
+            .assert_status_not_found();
+
+        // a purchased reservation stays sold
+        let response = server
+            .post("/purchases")
+            .json(&purchase(111, 10, "MD-2001"))
+            .await;
+        let reservation_id = response.json::<Value>()["reservation_id"].as_i64().unwrap();
+        server
+            .delete(&format!("/reservations/{reservation_id}"))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        assert_eq!(available(&server).await, 40);
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let server = test_server();
+        let api = server.get("/api-docs/openapi.json").await.json::<Value>();
+        let paths: Vec<&str> = api["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/orders",
+                "/orders/{id}",
+                "/products/{id}/stock",
+                "/purchases",
+                "/purchases/{id}",
+                "/reservations",
+                "/reservations/{id}"
+            ]
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use axum::extract::{Request, State};
+use axum::http::{header, uri::PathAndQuery, HeaderValue, StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::MethodRouter;
+use axum::Router;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Explicit version selection, e.g. `X-Api-Version: 2`
+pub const VERSION_HEADER: &str = "x-api-version";
+/// Vendor media type, e.g. `Accept: application/vnd.mydomain.v2+json`
+const VENDOR_MEDIA_TYPE: &str = "application/vnd.mydomain.v";
+
+#[derive(Debug, Clone)]
+pub struct ApiVersion {
+    number: u32,
+    deprecation: Option<Deprecation>,
+}
+
+#[derive(Debug, Clone)]
+struct Deprecation {
+    since: DateTime<Utc>,
+    sunset: Option<DateTime<Utc>>,
+}
+
+impl ApiVersion {
+    pub fn new(number: u32) -> ApiVersion {
+        ApiVersion {
+            number,
+            deprecation: None,
+        }
+    }
+
+    /// Responses of this version get `Deprecation` (RFC 9745) and `Sunset` (RFC 8594) headers
+    pub fn deprecated(mut self, since: DateTime<Utc>, sunset: Option<DateTime<Utc>>) -> Self {
+        self.deprecation = Some(Deprecation { since, sunset });
+        self
+    }
+}
+
+/// Builds `/v1`, `/v2`, ... routers: routes added with `route` are shared by all
+/// versions, `route_for` overrides (or adds) a route for one version only.
+pub struct VersionedApi<S> {
+    versions: Vec<ApiVersion>,
+    shared: Vec<(&'static str, MethodRouter<S>)>,
+    overrides: Vec<(u32, &'static str, MethodRouter<S>)>,
+}
+
+impl<S: Clone + Send + Sync + 'static> VersionedApi<S> {
+    pub fn new() -> VersionedApi<S> {
+        VersionedApi {
+            versions: Vec::new(),
+            shared: Vec::new(),
+            overrides: Vec::new(),
+        }
+    }
+
+    pub fn version(mut self, version: ApiVersion) -> Self {
+        self.versions.push(version);
+        self
+    }
+
+    pub fn route(mut self, path: &'static str, method_router: MethodRouter<S>) -> Self {
+        self.shared.push((path, method_router));
+        self
+    }
+
+    pub fn route_for(
+        mut self,
+        version: u32,
+        path: &'static str,
+        method_router: MethodRouter<S>,
+    ) -> Self {
+        self.overrides.push((version, path, method_router));
+        self
+    }
+
+    /// Negotiation settings for `negotiate_version`, unversioned requests get the latest
+    /// version that is not deprecated
+    pub fn negotiation(&self, prefix: &'static str) -> VersionNegotiation {
+        let supported: Vec<u32> = self.versions.iter().map(|v| v.number).collect();
+        let default = self
+            .versions
+            .iter()
+            .filter(|v| v.deprecation.is_none())
+            .map(|v| v.number)
+            .max()
+            .or_else(|| supported.iter().copied().max())
+            .expect("At least one API version is required");
+        VersionNegotiation {
+            prefix,
+            supported: Arc::new(supported),
+            default,
+        }
+    }
+
+    pub fn into_router(self) -> Router<S> {
+        let mut router = Router::new();
+        for version in self.versions {
+            let mut routes: BTreeMap<&'static str, MethodRouter<S>> =
+                self.shared.iter().cloned().collect();
+            for (_, path, method_router) in self
+                .overrides
+                .iter()
+                .filter(|(number, _, _)| *number == version.number)
+            {
+                routes.insert(path, method_router.clone());
+            }
+
+            let version_router = routes
+                .into_iter()
+                .fold(Router::new(), |r, (path, mr)| r.route(path, mr))
+                .layer(middleware::from_fn_with_state(
+                    Arc::new(version.clone()),
+                    add_version_headers,
+                ));
+            router = router.nest(&format!("/v{}", version.number), version_router);
+        }
+        router
+    }
+}
+
+async fn add_version_headers(
+    State(version): State<Arc<ApiVersion>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(VERSION_HEADER, HeaderValue::from(version.number));
+    if let Some(deprecation) = &version.deprecation {
+        headers.insert(
+            "deprecation",
+            HeaderValue::from_str(&format!("@{}", deprecation.since.timestamp())).unwrap(),
+        );
+        if let Some(sunset) = deprecation.sunset {
+            let sunset = httpdate::fmt_http_date(sunset.into());
+            headers.insert("sunset", HeaderValue::from_str(&sunset).unwrap());
+        }
+    }
+    response
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionNegotiation {
+    prefix: &'static str,
+    supported: Arc<Vec<u32>>,
+    default: u32,
+}
+
+/// Rewrites unversioned `{prefix}/...` requests to `{prefix}/v{N}/...`, the version is
+/// taken from `X-Api-Version`, then from a vendor media type in `Accept`. Has to wrap
+/// the whole router because routing happens on the rewritten path.
+pub async fn negotiate_version(
+    State(negotiation): State<VersionNegotiation>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let Some(rest) = path.strip_prefix(negotiation.prefix) else {
+        return next.run(request).await;
+    };
+    if !(rest.is_empty() || rest.starts_with('/')) || has_version_segment(rest) {
+        return next.run(request).await;
+    }
+
+    let version = match requested_version(&request) {
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+        Ok(None) => negotiation.default,
+        Ok(Some((version, _))) if negotiation.supported.contains(&version) => version,
+        Ok(Some((version, VersionSource::Header))) => {
+            return unsupported_version(&negotiation, version, StatusCode::BAD_REQUEST)
+        }
+        Ok(Some((version, VersionSource::Accept))) => {
+            return unsupported_version(&negotiation, version, StatusCode::NOT_ACCEPTABLE)
+        }
+    };
+
+    let versioned_path = format!("{}/v{version}{rest}", negotiation.prefix);
+    *request.uri_mut() = with_path(request.uri(), &versioned_path);
+
+    let mut response = next.run(request).await;
+    response.headers_mut().append(
+        header::VARY,
+        HeaderValue::from_static("accept, x-api-version"),
+    );
+    response
+}
+
+fn has_version_segment(rest: &str) -> bool {
+    rest.trim_start_matches('/')
+        .split('/')
+        .next()
+        .and_then(|segment| segment.strip_prefix('v'))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+enum VersionSource {
+    Header,
+    Accept,
+}
+
+fn requested_version(request: &Request) -> Result<Option<(u32, VersionSource)>, String> {
+    if let Some(value) = request.headers().get(VERSION_HEADER) {
+        return match value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().trim_start_matches('v').parse().ok())
+        {
+            Some(version) => Ok(Some((version, VersionSource::Header))),
+            None => Err(format!("Invalid {VERSION_HEADER} header")),
+        };
+    }
+    let vendor_version = request
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_type| {
+            let media_type = media_type.split(';').next()?.trim();
+            let version = media_type.strip_prefix(VENDOR_MEDIA_TYPE)?;
+            version.split('+').next()?.parse().ok()
+        })
+        .next();
+    Ok(vendor_version.map(|version| (version, VersionSource::Accept)))
+}
+
+fn unsupported_version(
+    negotiation: &VersionNegotiation,
+    version: u32,
+    status: StatusCode,
+) -> Response {
+    let supported = negotiation
+        .supported
+        .iter()
+        .map(|v| format!("v{v}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    (
+        status,
+        format!("API version v{version} is not supported, supported versions: {supported}"),
+    )
+        .into_response()
+}
+
+fn with_path(uri: &Uri, path: &str) -> Uri {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).unwrap());
+    Uri::from_parts(parts).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::routing::get;
+    use axum::ServiceExt;
+    use axum_test::TestServer;
+    use chrono::TimeZone;
+    use tower::Layer;
+
+    fn setup_server() -> TestServer {
+        let api = VersionedApi::new()
+            .version(ApiVersion::new(1).deprecated(
+                Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap(),
+                Some(Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap()),
+            ))
+            .version(ApiVersion::new(2))
+            .route("/users", get(async || "users v1"))
+            .route("/status", get(async || "status"))
+            .route_for(2, "/users", get(async || "users v2"));
+        let negotiation = api.negotiation("/api");
+        let router: Router = Router::new().nest("/api", api.into_router());
+        let app = middleware::from_fn_with_state(negotiation, negotiate_version).layer(router);
+        TestServer::new(ServiceExt::<Request>::into_make_service(app)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_version_by_path_prefix() {
+        let server = setup_server();
+
+        let response = server.get("/api/v1/users").await;
+        response.assert_text("users v1");
+        assert_eq!(response.header("deprecation"), "@1735689600");
+        assert_eq!(response.header("sunset"), "Fri, 01 Jan 2027 00:00:00 GMT");
+
+        let response = server.get("/api/v2/users").await;
+        response.assert_text("users v2");
+        assert!(response.maybe_header("deprecation").is_none());
+
+        // shared handler
+        server.get("/api/v1/status").await.assert_text("status");
+        server.get("/api/v2/status").await.assert_text("status");
+        server
+            .get("/api/v3/status")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_version_negotiation_by_headers() {
+        let server = setup_server();
+
+        let response = server.get("/api/users").await;
+        response.assert_text("users v2");
+        assert_eq!(response.header(VERSION_HEADER), "2");
+
+        let response = server
+            .get("/api/users")
+            .add_header(header::ACCEPT, "application/vnd.mydomain.v1+json")
+            .await;
+        response.assert_text("users v1");
+        assert_eq!(response.header("deprecation"), "@1735689600");
+
+        let response = server
+            .get("/api/users")
+            .add_header(VERSION_HEADER, "1")
+            .add_header(header::ACCEPT, "application/vnd.mydomain.v2+json")
+            .await;
+        response.assert_text("users v1");
+
+        server
+            .get("/api/users")
+            .add_header(header::ACCEPT, "application/vnd.mydomain.v7+json")
+            .await
+            .assert_status(StatusCode::NOT_ACCEPTABLE);
+        server
+            .get("/api/users")
+            .add_header(VERSION_HEADER, "7")
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        server
+            .get("/api/users")
+            .add_header(VERSION_HEADER, "latest")
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,308 @@
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{HeaderValue, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::rate_limit::RateLimit;
+
+/// `[http]` section of `config/{profile}.toml`
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpConfig {
+    pub allowed_origins: Vec<String>,
+    pub request_timeout_secs: u64,
+    // route path as registered in the router, e.g. "/wait/{millis}" -> seconds
+    #[serde(default)]
+    pub route_timeouts: HashMap<String, u64>,
+    pub static_files: Option<StaticFilesConfig>,
+    // rate limit group -> limit, e.g. "default" or "enqueue"
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimit>,
+}
+
+/// `[http.static_files]`: directory served under `mount`
+#[derive(Debug, Deserialize, Clone)]
+pub struct StaticFilesConfig {
+    pub mount: String,
+    pub dir: String,
+    // serve index.html for unknown paths (client-side routing)
+    #[serde(default)]
+    pub spa_fallback: bool,
+    #[serde(default)]
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpConfigError {
+    #[error("cannot load configuration: {0}")]
+    Load(#[from] config::ConfigError),
+    #[error("http.allowed_origins: '{0}' is not a valid origin, expected scheme://host[:port]")]
+    InvalidOrigin(String),
+    #[error("http.request_timeout_secs must be greater than 0")]
+    ZeroTimeout,
+    #[error("http.route_timeouts: '{0}' is not a route path, it must start with '/'")]
+    InvalidRoute(String),
+    #[error("http.route_timeouts.\"{0}\" must be greater than 0")]
+    ZeroRouteTimeout(String),
+    #[error("http.static_files.mount: '{0}' must start with '/' and cannot be the root")]
+    InvalidMount(String),
+    #[error("http.static_files.dir: '{0}' is not a directory")]
+    MissingStaticDir(String),
+    #[error("http.rate_limits.{0}: burst and per_second must be greater than 0")]
+    InvalidRateLimit(String),
+    #[error("log.level: '{0}' is not one of off, error, warn, info, debug or trace")]
+    InvalidLogLevel(String),
+}
+
+impl HttpConfig {
+    pub fn validate(&self) -> Result<(), HttpConfigError> {
+        for origin in &self.allowed_origins {
+            parse_origin(origin)?;
+        }
+        if self.request_timeout_secs == 0 {
+            return Err(HttpConfigError::ZeroTimeout);
+        }
+        for (route, secs) in &self.route_timeouts {
+            if !route.starts_with('/') {
+                return Err(HttpConfigError::InvalidRoute(route.clone()));
+            }
+            if *secs == 0 {
+                return Err(HttpConfigError::ZeroRouteTimeout(route.clone()));
+            }
+        }
+        if let Some(static_files) = &self.static_files {
+            if !static_files.mount.starts_with('/') || static_files.mount == "/" {
+                return Err(HttpConfigError::InvalidMount(static_files.mount.clone()));
+            }
+            if !Path::new(&static_files.dir).is_dir() {
+                return Err(HttpConfigError::MissingStaticDir(static_files.dir.clone()));
+            }
+        }
+        for (group, limit) in &self.rate_limits {
+            if limit.burst == 0 || limit.per_second.is_nan() || limit.per_second <= 0.0 {
+                return Err(HttpConfigError::InvalidRateLimit(group.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_allowed_origin(&self, origin: &HeaderValue) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').as_bytes() == origin.as_bytes())
+    }
+
+    pub fn route_timeouts(&self) -> RouteTimeouts {
+        RouteTimeouts {
+            default: Duration::from_secs(self.request_timeout_secs),
+            per_route: Arc::new(
+                self.route_timeouts
+                    .iter()
+                    .map(|(route, secs)| (route.clone(), Duration::from_secs(*secs)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn parse_origin(origin: &str) -> Result<HeaderValue, HttpConfigError> {
+    let invalid = || HttpConfigError::InvalidOrigin(origin.to_string());
+    let uri: Uri = origin.parse().map_err(|_| invalid())?;
+    let is_origin = matches!(uri.scheme_str(), Some("http" | "https"))
+        && uri.authority().is_some()
+        && uri.path_and_query().is_none_or(|pq| pq.as_str() == "/");
+    if !is_origin {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(origin.trim_end_matches('/')).map_err(|_| invalid())
+}
+
+#[derive(Clone)]
+pub struct RouteTimeouts {
+    default: Duration,
+    per_route: Arc<HashMap<String, Duration>>,
+}
+
+impl RouteTimeouts {
+    fn for_route(&self, route: Option<&str>) -> Duration {
+        route
+            .and_then(|route| self.per_route.get(route))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Replacement of `TimeoutLayer` that picks the limit by the matched route
+pub async fn route_timeout(
+    State(timeouts): State<RouteTimeouts>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str());
+    let timeout = timeouts.for_route(route);
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => StatusCode::REQUEST_TIMEOUT.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{body::Body, extract::Path, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn config() -> HttpConfig {
+        HttpConfig {
+            allowed_origins: vec!["http://localhost:3000".into()],
+            request_timeout_secs: 1,
+            route_timeouts: HashMap::from([("/slow/{millis}".into(), 5)]),
+            static_files: None,
+            rate_limits: HashMap::from([(
+                "default".into(),
+                RateLimit {
+                    burst: 5,
+                    per_second: 1.0,
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config().validate().is_ok());
+
+        let invalid = [
+            HttpConfig {
+                allowed_origins: vec!["localhost:3000".into()],
+                ..config()
+            },
+            HttpConfig {
+                request_timeout_secs: 0,
+                ..config()
+            },
+            HttpConfig {
+                route_timeouts: HashMap::from([("slow".into(), 5)]),
+                ..config()
+            },
+            HttpConfig {
+                route_timeouts: HashMap::from([("/slow/{millis}".into(), 0)]),
+                ..config()
+            },
+            HttpConfig {
+                static_files: Some(StaticFilesConfig {
+                    mount: "/".into(),
+                    dir: "src".into(),
+                    spa_fallback: false,
+                    max_age_secs: 0,
+                }),
+                ..config()
+            },
+            HttpConfig {
+                static_files: Some(StaticFilesConfig {
+                    mount: "/static".into(),
+                    dir: "no/such/dir".into(),
+                    spa_fallback: false,
+                    max_age_secs: 0,
+                }),
+                ..config()
+            },
+        ];
+        let errors: Vec<String> = invalid
+            .iter()
+            .map(|config| config.validate().unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "http.allowed_origins: 'localhost:3000' is not a valid origin, expected scheme://host[:port]",
+                "http.request_timeout_secs must be greater than 0",
+                "http.route_timeouts: 'slow' is not a route path, it must start with '/'",
+                "http.route_timeouts.\"/slow/{millis}\" must be greater than 0",
+                "http.static_files.mount: '/' must start with '/' and cannot be the root",
+                "http.static_files.dir: 'no/such/dir' is not a directory",
+            ]
+        );
+
+        for (burst, per_second) in [(0, 1.0), (5, 0.0), (5, -1.0), (5, f64::NAN)] {
+            let config = HttpConfig {
+                rate_limits: HashMap::from([("enqueue".into(), RateLimit { burst, per_second })]),
+                ..config()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(HttpConfigError::InvalidRateLimit(group)) if group == "enqueue"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_origin() {
+        assert_eq!(
+            parse_origin("https://example.com").unwrap(),
+            "https://example.com"
+        );
+        assert_eq!(
+            parse_origin("http://localhost:3000/").unwrap(),
+            "http://localhost:3000"
+        );
+        for origin in [
+            "",
+            "example.com",
+            "ftp://example.com",
+            "https://example.com/app",
+            "https://example.com/?debug=1",
+        ] {
+            assert!(
+                matches!(parse_origin(origin), Err(HttpConfigError::InvalidOrigin(_))),
+                "{origin}"
+            );
+        }
+
+        let config = HttpConfig {
+            allowed_origins: vec!["https://example.com/".into()],
+            ..config()
+        };
+        assert!(config.is_allowed_origin(&HeaderValue::from_static("https://example.com")));
+        assert!(!config.is_allowed_origin(&HeaderValue::from_static("https://example.org")));
+    }
+
+    async fn status(app: &Router, uri: &str) -> StatusCode {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_route_timeout() {
+        let sleep = async |Path(millis): Path<u64>| {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+        };
+        // layered on the whole router, as in main
+        let app = Router::new()
+            .route("/slow/{millis}", get(sleep))
+            .route("/sleep/{millis}", get(sleep))
+            .layer(middleware::from_fn_with_state(
+                config().route_timeouts(),
+                route_timeout,
+            ));
+
+        assert_eq!(status(&app, "/sleep/500").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/sleep/1500").await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+        // the route's own timeout wins over the default one
+        assert_eq!(status(&app, "/slow/1500").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/slow/5500").await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+}
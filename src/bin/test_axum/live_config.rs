@@ -0,0 +1,213 @@
+//! Configuration that can change while the server runs. The config files are polled,
+//! every valid edit is published as a new snapshot through a `watch` channel, invalid
+//! ones are logged and the last good snapshot stays in use.
+
+use axum::http::{request::Parts, HeaderValue};
+use rust_demo1::app_config;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::http_config::{HttpConfig, HttpConfigError};
+use crate::rate_limit::RateLimit;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LiveConfig {
+    pub http: HttpConfig,
+    pub log: LogConfig,
+}
+
+/// `[log]` section
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+impl LogConfig {
+    pub fn level_filter(&self) -> Result<LevelFilter, HttpConfigError> {
+        LevelFilter::from_str(&self.level)
+            .map_err(|_| HttpConfigError::InvalidLogLevel(self.level.clone()))
+    }
+}
+
+impl LiveConfig {
+    /// Loads `config/default.toml`, `config/{PROFILE}.toml` and `APP__*` env variables
+    pub fn load() -> Result<LiveConfig, HttpConfigError> {
+        let cfg = app_config::load_profile(&app_config::profile())?;
+        let live_config = LiveConfig {
+            http: cfg.get("http")?,
+            log: cfg.get("log")?,
+        };
+        live_config.http.validate()?;
+        live_config.log.level_filter()?;
+        Ok(live_config)
+    }
+
+    /// Reloads the config when `config/default.toml` or the profile file changes
+    pub fn watch(self) -> watch::Receiver<Arc<LiveConfig>> {
+        let files = vec![
+            PathBuf::from("config/default.toml"),
+            PathBuf::from(format!("config/{}.toml", app_config::profile())),
+        ];
+        watch_files(files, POLL_INTERVAL, self, LiveConfig::load)
+    }
+
+    pub fn rate_limit(&self, group: &str) -> Option<RateLimit> {
+        self.http.rate_limits.get(group).copied()
+    }
+}
+
+/// Calls `load` whenever the modification time of one of `files` changes and publishes
+/// its result. The task stops when all the receivers are dropped.
+pub fn watch_files<T, E, F>(
+    files: Vec<PathBuf>,
+    interval: Duration,
+    initial: T,
+    load: F,
+) -> watch::Receiver<Arc<T>>
+where
+    T: Send + Sync + 'static,
+    E: Display,
+    F: Fn() -> Result<T, E> + Send + 'static,
+{
+    let modified_times = move || {
+        files
+            .iter()
+            .map(|file| file.metadata().and_then(|m| m.modified()).ok())
+            .collect::<Vec<Option<SystemTime>>>()
+    };
+    watch_versions(modified_times, interval, initial, load)
+}
+
+/// Polls `version` every `interval` and calls `load` when it differs from the last one
+fn watch_versions<T, E, V, F>(
+    version: impl Fn() -> V + Send + 'static,
+    interval: Duration,
+    initial: T,
+    load: F,
+) -> watch::Receiver<Arc<T>>
+where
+    T: Send + Sync + 'static,
+    E: Display,
+    V: PartialEq + Send + 'static,
+    F: Fn() -> Result<T, E> + Send + 'static,
+{
+    let (config_snd, config_rcv) = watch::channel(Arc::new(initial));
+    // taken now, so that a change before the task runs isn't missed
+    let mut last_version = version();
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = config_snd.closed() => break,
+            }
+            let current = version();
+            if current == last_version {
+                continue;
+            }
+            last_version = current;
+            match load() {
+                Ok(config) => {
+                    tracing::info!("Configuration reloaded");
+                    config_snd.send_replace(Arc::new(config));
+                }
+                Err(e) => {
+                    tracing::error!("Configuration change rejected, keeping the last one: {e}")
+                }
+            }
+        }
+    });
+    config_rcv
+}
+
+/// Derived value of every snapshot, subscribers are only woken up when it changes
+pub fn subscribe<T, U>(
+    config: &watch::Receiver<Arc<T>>,
+    derive: impl Fn(&T) -> U + Send + 'static,
+) -> watch::Receiver<U>
+where
+    T: Send + Sync + 'static,
+    U: PartialEq + Send + Sync + 'static,
+{
+    let mut config = config.clone();
+    let (value_snd, value_rcv) = watch::channel(derive(&config.borrow_and_update()));
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                changed = config.changed() => if changed.is_err() { break },
+                _ = value_snd.closed() => break,
+            }
+            let value = derive(&config.borrow_and_update());
+            value_snd.send_if_modified(|current| {
+                let modified = *current != value;
+                if modified {
+                    *current = value;
+                }
+                modified
+            });
+        }
+    });
+    value_rcv
+}
+
+/// CORS layer checking `http.allowed_origins` of the latest snapshot
+pub fn cors_layer(config: watch::Receiver<Arc<LiveConfig>>) -> CorsLayer {
+    CorsLayer::new().allow_origin(AllowOrigin::predicate(
+        move |origin: &HeaderValue, _: &Parts| config.borrow().http.is_allowed_origin(origin),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_versions() {
+        // the contents of a config file, which are its version as well
+        let file = Arc::new(Mutex::new(String::from("1")));
+        let write = |contents: &str| *file.lock().unwrap() = contents.to_string();
+        let version = {
+            let file = file.clone();
+            move || file.lock().unwrap().clone()
+        };
+        let load = {
+            let file = file.clone();
+            move || {
+                let text = file.lock().unwrap().clone();
+                text.trim().parse::<u32>().map_err(|e| e.to_string())
+            }
+        };
+        let mut config = watch_versions(version, INTERVAL, 1, load);
+        let mut doubled = subscribe(&config, |value| value * 2);
+
+        write("2");
+        config.changed().await.unwrap();
+        assert_eq!(**config.borrow_and_update(), 2);
+        doubled.changed().await.unwrap();
+        assert_eq!(*doubled.borrow_and_update(), 4);
+
+        // an invalid edit keeps the last good value
+        write("two");
+        tokio::time::sleep(INTERVAL * 5).await;
+        assert!(!config.has_changed().unwrap());
+        assert_eq!(**config.borrow(), 2);
+
+        write("3");
+        config.changed().await.unwrap();
+        assert_eq!(**config.borrow_and_update(), 3);
+    }
+}
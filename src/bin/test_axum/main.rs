@@ -14,11 +14,15 @@ use axum::{
     http::{request::Parts, HeaderMap},
     Form, Json,
 };
-use axum::{http::StatusCode, routing::get, Router};
+use axum::{http::StatusCode, routing::get, Router, ServiceExt};
+use chrono::{TimeZone, Utc};
 use serde::Serialize;
 use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::{json, Value};
+use std::fmt::Display;
 use std::io::Cursor;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 use std::{
@@ -35,13 +39,24 @@ use std::{
     task::{Context, Poll},
 };
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::sync::{Mutex, RwLock};
 use tokio::time::Instant;
 use tower::{Layer, Service};
-use tower_http::cors::CorsLayer;
 use tower_http::services::ServeFile;
-use tower_http::timeout::TimeoutLayer;
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload};
+
+use api_version::{negotiate_version, ApiVersion, VersionedApi};
+use http_config::route_timeout;
+use live_config::LiveConfig;
+use rate_limit::{RateLimit, RateLimitLayer};
+
+mod api_version;
+mod http_config;
+mod live_config;
+mod rate_limit;
+mod static_files;
+mod word_events;
 
 tokio::task_local! {
     pub static SESSION: Arc<Mutex<SessionData>>;
@@ -55,20 +70,55 @@ struct AppState {
     counter: AtomicU64,
     sessions: RwLock<HashMap<String, Arc<Mutex<SessionData>>>>,
     word_snd: UnboundedSender<String>,
+    word_events: broadcast::Sender<String>,
+    streams_closing: watch::Receiver<bool>,
 }
 
 struct Session(String, Arc<Mutex<SessionData>>);
 
+// used when http.rate_limits has no entry for the group
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    burst: 50,
+    per_second: 20.0,
+};
+const ENQUEUE_RATE_LIMIT: RateLimit = RateLimit {
+    burst: 5,
+    per_second: 1.0,
+};
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+    // the level follows log.level of the config
+    let (log_filter, log_filter_handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let allowed_origins = [
-        "http://mydomain.com".parse().unwrap(),
-        "http://api.mydomain.com".parse().unwrap(),
-    ];
+    let metrics_recorder = metrics_prometheus::install();
+
+    let live_config = LiveConfig::load().unwrap_or_else(|e| {
+        tracing::error!("Invalid configuration: {e}");
+        std::process::exit(1);
+    });
+    // static files and route timeouts are only read at startup
+    let http_config = live_config.http.clone();
+    let live_config = live_config.watch();
+
+    let mut log_level = live_config::subscribe(&live_config, |cfg| {
+        cfg.log.level_filter().unwrap_or(LevelFilter::INFO)
+    });
+    tokio::spawn(async move {
+        loop {
+            let level = *log_level.borrow_and_update();
+            if let Err(e) = log_filter_handle.modify(|filter| *filter = level) {
+                tracing::error!("Cannot change the log level: {e}");
+            }
+            if log_level.changed().await.is_err() {
+                break;
+            }
+        }
+    });
 
     let sessions: RwLock<HashMap<String, Arc<Mutex<SessionData>>>> = {
         let mut data = HashMap::new();
@@ -83,19 +133,30 @@ async fn main() {
 
     let greeting = "Hello!".to_string();
 
-    let users_v1_router = Router::new().route("/users", get(list_users_v1));
-    let users_v2_router = Router::new().route("/users", get(list_users_v2));
+    // /api/v1/users, /api/v2/users or /api/users with X-Api-Version or a vendor media type
+    let users_api = VersionedApi::new()
+        .version(ApiVersion::new(1).deprecated(
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap(),
+            Some(Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap()),
+        ))
+        .version(ApiVersion::new(2))
+        .route("/users", get(list_users_v1))
+        .route_for(2, "/users", get(list_users_v2));
+    let api_negotiation = users_api.negotiation("/api");
 
     let (shutdown_snd, mut shutdown_rcv) = broadcast::channel::<()>(1);
     let (word_snd, mut word_rcv) = mpsc::unbounded_channel::<String>();
+    let (word_events, _) = broadcast::channel::<String>(100);
+    let (streams_closing_snd, streams_closing) = watch::channel(false);
 
     let bg_job = tokio::spawn({
+        let word_events = word_events.clone();
         async move {
             loop {
                 tokio::select! {
                     word_resp = word_rcv.recv() => {
                         if let Some(word) = word_resp {
-                            process_msg(word).await;
+                            process_msg(word, &word_events).await;
                         }
                     }
                     _ = shutdown_rcv.recv() => {
@@ -105,7 +166,7 @@ async fn main() {
                 }
             }
             while let Ok(word) = word_rcv.try_recv() {
-                process_msg(word).await;
+                process_msg(word, &word_events).await;
             }
             println!("> Worker is finished");
         }
@@ -114,13 +175,13 @@ async fn main() {
         counter: AtomicU64::new(0),
         sessions,
         word_snd,
+        word_events,
+        streams_closing,
     });
 
     let app = Router::new()
-        .layer(CorsLayer::new().allow_origin(allowed_origins))
         .fallback(my_fallback)
-        .nest("/api/v1", users_v1_router)
-        .nest("/api/v2", users_v2_router)
+        .nest("/api", users_api.into_router())
         .route("/users", post(create_user))
         .route("/users2", post(create_user2))
         .route("/users3", post(create_user3))
@@ -141,6 +202,7 @@ async fn main() {
         .route("/math/add/{arg1}/{arg2}", get(add))
         .route("/hello", get(hello))
         .route("/hello2", get(hello2))
+        .route("/items", get(list_items))
         .route_service(
             "/hello3",
             HelloService {
@@ -154,13 +216,21 @@ async fn main() {
         .route("/handler_5", get(handler_5))
         .route("/handler_6", get(handler_6))
         .route_service("/index", ServeFile::new("index.html"))
-        .route("/enqueue/{word}", get(handle_request))
+        .route(
+            "/enqueue/{word}",
+            get(handle_request).layer(RateLimitLayer::reloadable(
+                "enqueue",
+                live_config::subscribe(&live_config, |cfg| {
+                    cfg.rate_limit("enqueue").unwrap_or(ENQUEUE_RATE_LIMIT)
+                }),
+            )),
+        )
         .with_state(shared_state.clone())
         .layer(middleware::from_fn(log_exec_time))
         .layer(ExecTimeLogLayer)
-        .layer(TimeoutLayer::with_status_code(
-            StatusCode::REQUEST_TIMEOUT,
-            Duration::from_secs(10),
+        .layer(middleware::from_fn_with_state(
+            http_config.route_timeouts(),
+            route_timeout,
         ))
         .layer(from_fn(async |request: Request, next: Next| {
             tracing::info!("Middleware-1: before call");
@@ -175,8 +245,15 @@ async fn main() {
             response
         }))
         .layer(middleware::from_fn_with_state(
-            shared_state,
+            shared_state.clone(),
             set_session_for_request,
+        ))
+        .layer(live_config::cors_layer(live_config.clone()))
+        .layer(RateLimitLayer::reloadable(
+            "default",
+            live_config::subscribe(&live_config, |cfg| {
+                cfg.rate_limit("default").unwrap_or(DEFAULT_RATE_LIMIT)
+            }),
         )); // last middleware is first inside the chain
 
     // pros: each router instance can have its own state
@@ -186,7 +263,24 @@ async fn main() {
     let qproducts_router = Router::new()
         .route("/qproducts", get(list_products))
         .with_state(Arc::new(ProductState {}));
-    let app = app.merge(qusers_router).merge(qproducts_router);
+    // no session middleware: browser EventSource/WebSocket clients cannot send custom headers
+    let events_router = Router::new()
+        .route("/events", get(word_events::sse_handler))
+        .route("/ws", get(word_events::ws_handler))
+        .with_state(shared_state);
+    let metrics_router = Router::new()
+        .route("/metrics", get(get_metrics))
+        .with_state(Arc::new(metrics_recorder));
+    let app = match &http_config.static_files {
+        // no session middleware, same as for the events
+        Some(cfg) => app.nest_service(&cfg.mount, static_files::static_files_router(cfg)),
+        None => app,
+    };
+    let app = app
+        .merge(qusers_router)
+        .merge(qproducts_router)
+        .merge(events_router)
+        .merge(metrics_router);
 
     // Limitation: to create closure for handler function
     // fn make_hello_handler(greeting: String) -> impl AsyncFn() -> String {
@@ -201,12 +295,23 @@ async fn main() {
     // get, post, put, delete, patch, head, option и trace
     // any - for any http method
 
+    // wraps the whole router: the API version has to be in the path before routing
+    let app = middleware::from_fn_with_state(api_negotiation, negotiate_version).layer(app);
+
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    // connect info gives the rate limiter a client IP for requests without a session
+    axum::serve(
+        listener,
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        // SSE and WebSocket connections never finish on their own and would block the shutdown
+        let _ = streams_closing_snd.send(true);
+    })
+    .await
+    .unwrap();
 
     println!("> Sending shutdown command to workers");
     let _ = shutdown_snd.send(());
@@ -224,9 +329,11 @@ async fn handle_request(
     }
 }
 
-async fn process_msg(w: String) {
+async fn process_msg(w: String, word_events: &broadcast::Sender<String>) {
     tokio::time::sleep(Duration::from_secs(1)).await;
     println!("Word: {w}");
+    // error only means that nobody is listening right now
+    let _ = word_events.send(w);
 }
 
 async fn shutdown_signal() {
@@ -441,24 +548,83 @@ impl<S: Send + Sync> FromRequestParts<S> for SessionId {
     }
 }
 
-struct MyQueryParams(HashMap<String, String>);
+/// Query string decoded as `application/x-www-form-urlencoded` (the URL spec parser):
+/// keys and values are percent-decoded, `+` becomes a space, a key without `=` gets an
+/// empty value and repeated keys keep all of their values in order.
+struct MyQueryParams(HashMap<String, Vec<String>>);
+
+impl MyQueryParams {
+    fn parse(query_string: &str) -> MyQueryParams {
+        let mut params: HashMap<String, Vec<String>> = HashMap::new();
+        for (k, v) in form_urlencoded::parse(query_string.as_bytes()) {
+            params
+                .entry(k.into_owned())
+                .or_default()
+                .push(v.into_owned());
+        }
+        MyQueryParams(params)
+    }
+
+    fn get_all(&self, name: &str) -> &[String] {
+        self.0.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    fn first(&self, name: &str) -> Option<&str> {
+        self.get_all(name).first().map(String::as_str)
+    }
+
+    /// Typed access to a required parameter, e.g. `params.get::<u32>("page")?`
+    fn get<T>(&self, name: &str) -> Result<T, QueryParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get_opt(name)?
+            .ok_or_else(|| QueryParamError::Missing(name.to_string()))
+    }
+
+    fn get_opt<T>(&self, name: &str) -> Result<Option<T>, QueryParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.first(name) {
+            Some(value) => value
+                .parse::<T>()
+                .map(Some)
+                .map_err(|e| QueryParamError::Invalid {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    reason: e.to_string(),
+                }),
+            None => Ok(None),
+        }
+    }
+}
 
 impl<S: Send + Sync> FromRequestParts<S> for MyQueryParams {
-    type Rejection = ();
+    type Rejection = Infallible;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let mut params = HashMap::new();
-        if let Some(query_string) = parts.uri.query() {
-            for pair in query_string.split("&") {
-                let mut kv = pair.split("=");
-                if let Some(k) = kv.next() {
-                    if let Some(v) = kv.next() {
-                        params.insert(k.to_string(), v.to_string());
-                    }
-                }
-            }
-        }
-        Ok(MyQueryParams(params))
+        Ok(MyQueryParams::parse(parts.uri.query().unwrap_or_default()))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum QueryParamError {
+    #[error("Missing query parameter '{0}'")]
+    Missing(String),
+    #[error("Invalid value '{value}' of query parameter '{name}': {reason}")]
+    Invalid {
+        name: String,
+        value: String,
+        reason: String,
+    },
+}
+
+impl IntoResponse for QueryParamError {
+    fn into_response(self) -> Response {
+        (StatusCode::BAD_REQUEST, self.to_string()).into_response()
     }
 }
 
@@ -559,6 +725,15 @@ async fn hello2(
     )
 }
 
+async fn list_items(params: MyQueryParams) -> Result<String, QueryParamError> {
+    let page: u32 = params.get("page")?;
+    let per_page: u32 = params.get_opt("per_page")?.unwrap_or(20);
+    let tags = params.get_all("tag");
+    Ok(format!(
+        "Page: {page}, per page: {per_page}, tags: {tags:?}"
+    ))
+}
+
 async fn hello(headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Response {
     let headers_string = headers
         .iter()
@@ -650,6 +825,12 @@ async fn list_products(State(state): State<Arc<ProductState>>) -> String {
     format!("Products endpoint. State: {state:?}")
 }
 
+async fn get_metrics(State(recorder): State<Arc<metrics_prometheus::Recorder>>) -> String {
+    prometheus::TextEncoder::new()
+        .encode_to_string(&recorder.registry().gather())
+        .unwrap()
+}
+
 async fn list_users_v1() -> &'static str {
     "Users endpoint Version 1"
 }
@@ -657,3 +838,43 @@ async fn list_users_v1() -> &'static str {
 async fn list_users_v2() -> &'static str {
     "Users endpoint Version 2"
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_params_decoding() {
+        let params = MyQueryParams::parse("name=John%20Doe&city=New+York&flag&tag=a&tag=b%26c");
+
+        assert_eq!(params.first("name"), Some("John Doe"));
+        assert_eq!(params.first("city"), Some("New York"));
+        assert_eq!(params.first("flag"), Some(""));
+        assert_eq!(params.get_all("tag"), ["a", "b&c"]);
+        assert!(params.get_all("missing").is_empty());
+    }
+
+    #[test]
+    fn test_query_params_typed_access() {
+        let params = MyQueryParams::parse("page=3&per_page=abc");
+
+        assert_eq!(params.get::<u32>("page").unwrap(), 3);
+        assert_eq!(params.get_opt::<u32>("limit").unwrap(), None);
+        assert!(matches!(
+            params.get::<u32>("limit"),
+            Err(QueryParamError::Missing(_))
+        ));
+        assert!(matches!(
+            params.get::<u32>("per_page"),
+            Err(QueryParamError::Invalid { .. })
+        ));
+        assert_eq!(
+            params
+                .get::<u32>("per_page")
+                .unwrap_err()
+                .into_response()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
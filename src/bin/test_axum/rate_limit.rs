@@ -0,0 +1,260 @@
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tower::{Layer, Service};
+
+// above this number of tracked clients the idle (fully refilled) buckets are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+// a very slow refill would ask to come back in ages (or overflow a Duration), cap it
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Token bucket settings: up to `burst` requests at once, refilled by `per_second` tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Takes one token or returns how long to wait until the next one is available
+    fn try_acquire(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second);
+            Err(wait.map_or(MAX_RETRY_AFTER, |wait| wait.min(MAX_RETRY_AFTER)))
+        }
+    }
+}
+
+type Buckets = Arc<Mutex<HashMap<String, TokenBucket>>>;
+
+/// Limits requests per client: by `sessionid` header, or by client IP when there is no
+/// session. Each layer instance keeps its own buckets, so different route groups can
+/// have different limits.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    group: &'static str,
+    limit: watch::Receiver<RateLimit>,
+    buckets: Buckets,
+}
+
+impl RateLimitLayer {
+    /// Picks up every new limit sent to the channel, the buckets are kept
+    pub fn reloadable(group: &'static str, limit: watch::Receiver<RateLimit>) -> RateLimitLayer {
+        RateLimitLayer {
+            group,
+            limit,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            next_handler: inner,
+            group: self.group,
+            limit: self.limit.clone(),
+            buckets: self.buckets.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    next_handler: S,
+    group: &'static str,
+    limit: watch::Receiver<RateLimit>,
+    buckets: Buckets,
+}
+
+impl<S> RateLimitService<S> {
+    fn acquire(&self, client_key: String) -> Result<(), Duration> {
+        let limit = *self.limit.borrow();
+        let now = Instant::now();
+        let mut guard = self.buckets.lock().unwrap();
+        if guard.len() >= MAX_TRACKED_CLIENTS {
+            guard.retain(|_, bucket| {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+        }
+        guard
+            .entry(client_key)
+            .or_insert_with(|| TokenBucket::full(&limit, now))
+            .try_acquire(&limit, now)
+    }
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.next_handler.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        match self.acquire(client_key(&request)) {
+            Ok(()) => Box::pin(self.next_handler.call(request)),
+            Err(retry_after) => {
+                metrics::counter!("rate_limit_rejections_total", "group" => self.group)
+                    .increment(1);
+                tracing::warn!("Rate limit exceeded for group {}", self.group);
+                let response = too_many_requests(retry_after);
+                Box::pin(async move { Ok(response) })
+            }
+        }
+    }
+}
+
+fn client_key(request: &Request) -> String {
+    if let Some(session_id) = request
+        .headers()
+        .get("sessionid")
+        .and_then(|v| v.to_str().ok())
+    {
+        format!("session:{session_id}")
+    } else if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        format!("ip:{}", addr.ip())
+    } else {
+        "unknown".to_string()
+    }
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    // Retry-After is in whole seconds, round up so the client doesn't come back too early
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, HeaderValue::from(seconds))],
+        "Too many requests",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn request(session_id: &str) -> Request {
+        Request::builder()
+            .uri("/")
+            .header("sessionid", session_id)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_per_session() {
+        let limit = RateLimit {
+            burst: 2,
+            per_second: 0.5,
+        };
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            // the receiver keeps the last value after the sender is dropped
+            .layer(RateLimitLayer::reloadable("test", watch::channel(limit).1));
+
+        for _ in 0..2 {
+            let response = app.clone().oneshot(request("session-1")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+
+        // another session has its own bucket
+        let response = app.clone().oneshot(request("session-2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reloaded_limit() {
+        let (limit_snd, limit) = watch::channel(RateLimit {
+            burst: 1,
+            per_second: 0.1,
+        });
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(RateLimitLayer::reloadable("test", limit));
+
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // a faster refill applies to the existing bucket
+        limit_snd.send_replace(RateLimit {
+            burst: 1,
+            per_second: 10.0,
+        });
+        tokio::time::advance(Duration::from_millis(100)).await;
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tiny_refill_rate() {
+        let limit = RateLimit {
+            burst: 1,
+            per_second: 1e-300,
+        };
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(RateLimitLayer::reloadable("test", watch::channel(limit).1));
+
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("session-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "86400");
+    }
+}
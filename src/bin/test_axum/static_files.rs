@@ -0,0 +1,153 @@
+use crate::http_config::StaticFilesConfig;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tower_http::services::{ServeDir, ServeFile};
+use tower_http::set_header::SetResponseHeaderLayer;
+
+/// Directory mount: `index.html` for directories, `.gz`/`.br` precompressed variants,
+/// Last-Modified/ETag with conditional GETs and, optionally, `index.html` for unknown
+/// paths so client-side routing of a SPA works.
+pub fn static_files_router(cfg: &StaticFilesConfig) -> Router {
+    let dir = Path::new(&cfg.dir);
+    let serve_dir = ServeDir::new(dir)
+        .append_index_html_on_directories(true)
+        .precompressed_gzip()
+        .precompressed_br();
+
+    let router = if cfg.spa_fallback {
+        let index = ServeFile::new(dir.join("index.html"))
+            .precompressed_gzip()
+            .precompressed_br();
+        Router::new().fallback_service(serve_dir.fallback(index))
+    } else {
+        Router::new().fallback_service(serve_dir)
+    };
+
+    let cache_control = HeaderValue::from_str(&format!("public, max-age={}", cfg.max_age_secs))
+        .expect("Cannot build cache-control header");
+
+    router
+        .layer(middleware::from_fn(etag))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::CACHE_CONTROL,
+            cache_control,
+        ))
+        // caches must keep the precompressed variants apart
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::VARY,
+            HeaderValue::from_static("accept-encoding"),
+        ))
+}
+
+/// `ServeDir` handles `If-Modified-Since` only, so the weak ETag is derived from
+/// the same metadata: size, modification time and the chosen encoding.
+async fn etag(request: Request, next: Next) -> Response {
+    let if_none_match = request.headers().get(header::IF_NONE_MATCH).cloned();
+    let is_read = matches!(*request.method(), Method::GET | Method::HEAD);
+    let mut response = next.run(request).await;
+
+    if !is_read || response.status() != StatusCode::OK {
+        return response;
+    }
+    let Some(etag) = compute_etag(response.headers()) else {
+        return response;
+    };
+    if if_none_match.is_some_and(|value| etag_matches(&value, &etag)) {
+        let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+        for name in [
+            header::LAST_MODIFIED,
+            header::CONTENT_ENCODING,
+            header::VARY,
+        ] {
+            if let Some(value) = response.headers().get(&name) {
+                not_modified.headers_mut().insert(name, value.clone());
+            }
+        }
+        not_modified.headers_mut().insert(header::ETAG, etag);
+        return not_modified;
+    }
+    response.headers_mut().insert(header::ETAG, etag);
+    response
+}
+
+fn compute_etag(headers: &HeaderMap) -> Option<HeaderValue> {
+    let last_modified = headers.get(header::LAST_MODIFIED)?.to_str().ok()?;
+    let modified_secs = httpdate::parse_http_date(last_modified)
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let length = headers.get(header::CONTENT_LENGTH)?.to_str().ok()?;
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|e| format!("-{e}"))
+        .unwrap_or_default();
+    HeaderValue::from_str(&format!("W/\"{length}-{modified_secs:x}{encoding}\"")).ok()
+}
+
+// weak comparison as required for If-None-Match
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let etag = etag.to_str().unwrap_or_default().trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum_test::TestServer;
+
+    #[tokio::test]
+    async fn test_static_files() {
+        let dir = std::env::temp_dir().join(format!("test_axum_static_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>SPA</h1>").unwrap();
+        std::fs::write(dir.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+        std::fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+
+        let cfg = StaticFilesConfig {
+            mount: "/static".to_string(),
+            dir: dir.to_string_lossy().to_string(),
+            spa_fallback: true,
+            max_age_secs: 60,
+        };
+        let server =
+            TestServer::new(Router::new().nest_service("/static", static_files_router(&cfg)))
+                .unwrap();
+
+        let response = server.get("/static/docs/").await;
+        response.assert_status_ok();
+        response.assert_text("<h1>Docs</h1>");
+
+        let response = server.get("/static/app.js").await;
+        response.assert_status_ok();
+        assert_eq!(response.header(header::CACHE_CONTROL), "public, max-age=60");
+        let etag = response.header(header::ETAG);
+        assert!(etag.to_str().unwrap().starts_with("W/\"14-"));
+
+        let response = server
+            .get("/static/app.js")
+            .add_header(header::IF_NONE_MATCH, etag.clone())
+            .await;
+        response.assert_status(StatusCode::NOT_MODIFIED);
+        assert_eq!(response.header(header::ETAG), etag);
+
+        // client-side route of the SPA
+        let response = server.get("/static/users/42").await;
+        response.assert_status_ok();
+        response.assert_text("<h1>SPA</h1>");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use crate::AppState;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use futures::{Stream, StreamExt};
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+
+/// Streams processed words as `word` events until the server starts shutting down
+pub async fn sse_handler(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut streams_closing = state.streams_closing.clone();
+    let words = BroadcastStream::new(state.word_events.subscribe())
+        .filter_map(async |msg| match msg {
+            Ok(word) => Some(Ok(Event::default().event("word").data(word))),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                tracing::warn!("SSE client lagged behind, skipped {skipped} words");
+                None
+            }
+        })
+        .take_until(async move {
+            let _ = streams_closing.changed().await;
+        });
+    Sse::new(words).keep_alive(KeepAlive::default())
+}
+
+pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+    ws.on_upgrade(move |socket| {
+        stream_words(
+            socket,
+            state.word_events.subscribe(),
+            state.streams_closing.clone(),
+        )
+    })
+}
+
+async fn stream_words(
+    mut socket: WebSocket,
+    mut words: broadcast::Receiver<String>,
+    mut streams_closing: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            word = words.recv() => match word {
+                Ok(word) => {
+                    if socket.send(Message::Text(word.into())).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("WebSocket client lagged behind, skipped {skipped} words");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                // client messages are ignored, pings are answered by axum
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+            _ = streams_closing.changed() => break,
+        }
+    }
+
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "Server is shutting down".into(),
+        })))
+        .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{body::Body, extract::Request, routing::get, Router};
+    use axum_test::{TestServer, WsMessage};
+    use std::{collections::HashMap, sync::atomic::AtomicU64, time::Duration};
+    use tokio::sync::{mpsc, RwLock};
+    use tower::ServiceExt;
+
+    const WORDS: [&str; 3] = ["alpha", "beta", "gamma"];
+
+    // the sender of `streams_closing` stays with the test
+    fn app() -> (Router, Arc<AppState>, watch::Sender<bool>) {
+        let (streams_closing_snd, streams_closing) = watch::channel(false);
+        let state = Arc::new(AppState {
+            counter: AtomicU64::new(0),
+            sessions: RwLock::new(HashMap::new()),
+            word_snd: mpsc::unbounded_channel().0,
+            word_events: broadcast::channel(100).0,
+            streams_closing,
+        });
+        let app = Router::new()
+            .route("/events", get(sse_handler))
+            .route("/ws", get(ws_handler))
+            .with_state(state.clone());
+        (app, state, streams_closing_snd)
+    }
+
+    // words sent before a client subscribes are not delivered to it
+    async fn wait_for_subscriber(state: &AppState) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.word_events.receiver_count() == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("client subscribed");
+    }
+
+    #[tokio::test]
+    async fn test_sse_streams_words_until_closing() {
+        let (app, state, streams_closing) = app();
+        let request = Request::builder()
+            .uri("/events")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        wait_for_subscriber(&state).await;
+
+        for word in WORDS {
+            state.word_events.send(word.to_string()).unwrap();
+        }
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while received.matches("\n\n").count() < WORDS.len() {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("word event")
+                .unwrap()
+                .unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        assert_eq!(
+            received,
+            "event: word\ndata: alpha\n\nevent: word\ndata: beta\n\nevent: word\ndata: gamma\n\n"
+        );
+
+        streams_closing.send(true).unwrap();
+        let end = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("stream ended");
+        assert!(end.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_streams_words_until_closing() {
+        let (app, state, streams_closing) = app();
+        let server = TestServer::builder().http_transport().build(app).unwrap();
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        wait_for_subscriber(&state).await;
+
+        for word in WORDS {
+            state.word_events.send(word.to_string()).unwrap();
+        }
+        for word in WORDS {
+            websocket.assert_receive_text(word).await;
+        }
+
+        streams_closing.send(true).unwrap();
+        match websocket.receive_message().await {
+            WsMessage::Close(Some(frame)) => assert_eq!(u16::from(frame.code), close_code::AWAY),
+            message => panic!("expected a close frame, got {message:?}"),
+        }
+    }
+}
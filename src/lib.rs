@@ -0,0 +1,9 @@
+//! Code shared by the binaries of this package
+
+pub mod app_config;
+pub mod migrate;
+pub mod secret;
+pub mod shop;
+
+#[cfg(test)]
+mod test_db;
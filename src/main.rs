@@ -2688,6 +2688,7 @@ fn main() {
         // another usage example:
         // let addr: &Address = s.as_ref();
         // let addr = AsRef::<Address>::as_ref(&s);
+        // rust_demo1::shop::Shipment does the same with its validated Address
     }
 
     {
@@ -3990,215 +3991,74 @@ fn main() {
     }
 
     {
-        use std::{
-            collections::HashMap,
-            sync::{
-                atomic::{AtomicU64, Ordering},
-                Arc, Mutex,
-            },
+        // the services are in src/shop.rs, with Postgres implementations in src/shop/pg.rs
+        // and served over HTTP by src/bin/shop_api
+        use futures::executor::block_on;
+        use rust_demo1::shop::{
+            Address, Fulfillment, InMemoryOrderLog, InMemoryReservationService, InMemorySagaLog,
+            InMemoryShipmentService, OrderItem, PurchaseService, SagaPurchaseService,
         };
-
-        #[derive(Debug)]
-        #[allow(dead_code)]
-        struct Reservation {
-            id: u64,
-            product_id: u64,
-            quantity: u64,
-        }
-
-        #[derive(Debug, thiserror::Error)]
-        #[allow(dead_code)]
-        enum ResereError {
-            #[error("No product with ID {id}")]
-            NoSuchProduct { id: u64 },
-            #[error("Asked {asked}, but available {available}")]
-            NotEnought { asked: u64, available: u64 },
-        }
-
-        trait ReservationService {
-            fn reserve(&self, id: u64, quantity: u64) -> Result<Reservation, ResereError>;
-        }
-
-        struct ReservationImpl {
-            storage: Mutex<HashMap<u64, u64>>,
-            last_id: AtomicU64,
-        }
-
-        impl ReservationImpl {
-            fn new(storage: HashMap<u64, u64>) -> ReservationImpl {
-                ReservationImpl {
-                    storage: Mutex::new(storage),
-                    last_id: AtomicU64::new(0),
-                }
-            }
-        }
-
-        impl ReservationService for ReservationImpl {
-            fn reserve(&self, id: u64, quantity: u64) -> Result<Reservation, ResereError> {
-                let mut guard = self.storage.lock().unwrap();
-                if let Some(stock) = guard.get_mut(&id) {
-                    if *stock < quantity {
-                        Err(ResereError::NotEnought {
-                            asked: quantity,
-                            available: *stock,
-                        })
-                    } else {
-                        *stock -= quantity;
-                        Ok(Reservation {
-                            id: self.last_id.fetch_add(1, Ordering::Relaxed),
-                            product_id: id,
-                            quantity,
-                        })
-                    }
-                } else {
-                    Err(ResereError::NoSuchProduct { id })
-                }
-            }
-        }
-
-        #[allow(dead_code)]
-        struct Shipment {
-            id: u64,
-            address: String,
-            reservation_id: u64,
-        }
-
-        #[derive(Debug, thiserror::Error)]
-        enum ShipmentError {
-            #[error("Invalid address: {address}")]
-            InvalidAddress { address: String },
-        }
-
-        trait ShipmentService {
-            fn schedule_shipment(
-                &self,
-                reservation: &Reservation,
-                address: &str,
-            ) -> Result<Shipment, ShipmentError>;
-        }
-
-        #[derive(Debug)]
-        struct ShipmentImpl {
-            last_id: AtomicU64,
-        }
-
-        impl ShipmentImpl {
-            fn new() -> ShipmentImpl {
-                ShipmentImpl {
-                    last_id: AtomicU64::new(0),
-                }
-            }
-        }
-
-        impl ShipmentService for ShipmentImpl {
-            fn schedule_shipment(
-                &self,
-                reservation: &Reservation,
-                address: &str,
-            ) -> Result<Shipment, ShipmentError> {
-                if address.split(" ").count() < 2 {
-                    Err(ShipmentError::InvalidAddress {
-                        address: address.to_string(),
-                    })
-                } else {
-                    Ok(Shipment {
-                        id: self.last_id.fetch_add(1, Ordering::Relaxed),
-                        address: address.to_string(),
-                        reservation_id: reservation.id,
-                    })
-                }
-            }
-        }
-
-        #[derive(Debug)]
-        #[allow(dead_code)]
-        struct Purchase {
-            id: u64,
-            reservation_id: u64,
-            shipment_id: u64,
-        }
-
-        #[derive(Debug, thiserror::Error)]
-        enum PurchaseError {
-            #[error("Nested servation error: (0)")]
-            ReservationFailed(#[from] ResereError),
-            #[error("Nested shipping error: (0)")]
-            ShippingFailed(#[from] ShipmentError),
-        }
-
-        trait PurchaseService {
-            fn purchase(
-                &self,
-                id: u64,
-                quantity: u64,
-                addr: &str,
-            ) -> Result<Purchase, PurchaseError>;
-        }
-
-        struct PurchaseImpl {
-            reservation_service: Arc<dyn ReservationService>,
-            shipment_service: Arc<dyn ShipmentService>,
-            last_id: AtomicU64,
-        }
-
-        impl PurchaseImpl {
-            fn new(
-                reservation_service: Arc<dyn ReservationService>,
-                shipment_service: Arc<dyn ShipmentService>,
-            ) -> PurchaseImpl {
-                PurchaseImpl {
-                    reservation_service,
-                    shipment_service,
-                    last_id: AtomicU64::new(0),
-                }
-            }
-        }
-
-        impl PurchaseService for PurchaseImpl {
-            fn purchase(
-                &self,
-                id: u64,
-                quantity: u64,
-                addr: &str,
-            ) -> Result<Purchase, PurchaseError> {
-                let reservation = self.reservation_service.reserve(id, quantity)?;
-                let shipment = self
-                    .shipment_service
-                    .schedule_shipment(&reservation, addr)?;
-                Ok(Purchase {
-                    id: self.last_id.fetch_add(1, Ordering::Relaxed),
-                    reservation_id: reservation.id,
-                    shipment_id: shipment.id,
-                })
-            }
-        }
+        use std::{collections::HashMap, sync::Arc};
 
         fn initialize_purchase_service() -> Arc<dyn PurchaseService> {
             let mut products = HashMap::new();
             products.insert(111, 50);
-            let reservation_service = ReservationImpl::new(products);
-
-            let shipment_service = ShipmentImpl::new();
-
-            let purchase_service =
-                PurchaseImpl::new(Arc::new(reservation_service), Arc::new(shipment_service));
+            products.insert(222, 5);
+            let reservation_service =
+                InMemoryReservationService::new(products).with_warehouse(222, 1001);
+
+            let shipment_service = InMemoryShipmentService::new();
+
+            // a failed shipment gives the reserved stock back
+            let purchase_service = SagaPurchaseService::new(
+                Arc::new(reservation_service),
+                Arc::new(shipment_service),
+                Arc::new(InMemorySagaLog::new()),
+                Arc::new(InMemoryOrderLog::new()),
+            );
 
             Arc::new(purchase_service)
         }
 
         let purchase_service = initialize_purchase_service();
 
-        println!("{:?}", purchase_service.purchase(112, 1, "addr 1"));
+        // the postal code follows the rules of the country
+        println!("{:?}", Address::new("Stefan cel Mare 1", "Chisinau", "2001-MD", "MD"));
+        // Err(InvalidAddress { field: PostalCode, reason: "\"2001-MD\" is not one of MD-9999, 9999 in MD" })
+        let address = Address::new("Stefan cel Mare 1", "Chisinau", "MD-2001", "MD").unwrap();
+
+        println!("{:?}", block_on(purchase_service.purchase(112, 1, &address)));
         // Err(ReservationFailed(NoSuchProduct { id: 112 }))
 
-        println!("{:?}", purchase_service.purchase(111, 51, "addr 1"));
-        // Err(ReservationFailed(NotEnought { asked: 51, available: 50 }))
+        println!("{:?}", block_on(purchase_service.purchase(111, 51, &address)));
+        // Err(ReservationFailed(NotEnough { asked: 51, available: 50 }))
 
-        println!("{:?}", purchase_service.purchase(111, 10, "invalid"));
-        // Err(ShippingFailed(InvalidAddress { address: "invalid" }))
+        println!("{:?}", block_on(purchase_service.purchase(111, 10, &address)));
+        // Ok(Purchase { id: 1002, reservation_id: 1000, shipment_id: 1000 })
 
-        println!("{:?}", purchase_service.purchase(111, 10, "addr 1"));
-        // Ok(Purchase { id: 0, reservation_id: 1, shipment_id: 0 })
+        // several products, the missing stock is backordered
+        let items = [
+            OrderItem {
+                product_id: 111,
+                quantity: 5,
+            },
+            OrderItem {
+                product_id: 222,
+                quantity: 8,
+            },
+        ];
+        let order =
+            block_on(purchase_service.place_order(&items, &address, Fulfillment::Partial)).unwrap();
+        for line in &order.lines {
+            println!(
+                "product {}: reserved {}, backordered {}",
+                line.product_id, line.reserved, line.backordered
+            );
+        }
+        // product 111: reserved 5, backordered 0
+        // product 222: reserved 5, backordered 3
+        println!("{} shipments", order.shipments.len());
+        // 2 shipments, one per warehouse
     }
 
     {
@@ -4936,44 +4796,17 @@ fn main() {
 
     {
         // config dependency, work with .toml config file
+        // config/default.toml, then config/{PROFILE}.toml, then env variables:
+        // APP__db__host=qwerty will reset db.host to this value
+        // see src/app_config.rs, the module is shared by all the sqlx binaries
 
-        use config::{Config, File};
-        use serde::Deserialize;
-
-        #[allow(dead_code)]
-        #[derive(Debug, Deserialize, Clone)]
-        struct AppConfig {
-            db: DbConfig,
-            server: ServerConfig,
-        }
+        use rust_demo1::app_config::AppConfig;
 
-        #[allow(dead_code)]
-        #[derive(Debug, Deserialize, Clone)]
-        struct DbConfig {
-            host: String,
-            port: u32,
-            login: String,
-            password: String,
+        // invalid values are reported by key, e.g. "db.port: 70000 is not between 1 and 65535"
+        match AppConfig::load() {
+            Ok(app_config) => println!("{app_config:?}"), // passwords are printed as [REDACTED]
+            Err(e) => println!("{e}"),
         }
-
-        #[allow(dead_code)]
-        #[derive(Debug, Deserialize, Clone)]
-        struct ServerConfig {
-            listen_port: u32,
-        }
-
-        let profile = std::env::var("PROFILE").unwrap_or_else(|_| "dev".into());
-
-        let cfg = Config::builder()
-            .add_source(File::with_name("config/default.toml"))
-            .add_source(File::with_name(&format!("config/{profile}.toml")))
-            .add_source(config::Environment::with_prefix("app").separator("__")) // define env variable APP__db__host=qwerty will reset db.host to this value
-            .build()
-            .unwrap();
-
-        let app_config: AppConfig = cfg.try_deserialize().unwrap();
-
-        println!("{app_config:?}");
     }
 
     {
@@ -0,0 +1,237 @@
+//! Migrations from `migrations/`, embedded into the binaries at compile time
+
+use sqlx::{
+    migrate::{Migrate, MigrateError, Migration, Migrator},
+    PgPool,
+};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+/// Every known migration and whether it has been applied to the database
+pub async fn status(db: &PgPool) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let applied = applied_versions(db).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            applied: applied.contains(&m.version),
+        })
+        .collect())
+}
+
+/// Applies all the pending migrations
+pub async fn up(db: &PgPool) -> Result<(), MigrateError> {
+    let mut conn = db.acquire().await?;
+    let result = MIGRATOR.run(&mut *conn).await;
+    if result.is_err() {
+        // a failed migration returns before the unlock, the pooled connection would keep
+        // the lock; unlocking a lock that isn't held only warns
+        conn.unlock().await?;
+    }
+    result
+}
+
+/// Reverts the last applied migration, returns its version or `None` when nothing was applied
+pub async fn down(db: &PgPool) -> Result<Option<i64>, MigrateError> {
+    let applied = applied_versions(db).await?;
+    let Some(&last) = applied.last() else {
+        return Ok(None);
+    };
+    run_locked(db, migration(last, false)?).await?;
+    Ok(Some(last))
+}
+
+/// Reverts the last applied migration and applies it again
+pub async fn redo(db: &PgPool) -> Result<Option<i64>, MigrateError> {
+    let Some(version) = down(db).await? else {
+        return Ok(None);
+    };
+    // only the reverted one, migrations pending after it stay pending
+    run_locked(db, migration(version, true)?).await?;
+    Ok(Some(version))
+}
+
+fn migration(version: i64, up: bool) -> Result<&'static Migration, MigrateError> {
+    MIGRATOR
+        .iter()
+        .find(|m| m.version == version && m.migration_type.is_up_migration() == up)
+        .ok_or(MigrateError::VersionMissing(version))
+}
+
+/// Applies or reverts `migration` holding the migration lock, which is released even
+/// when the migration fails
+async fn run_locked(db: &PgPool, migration: &Migration) -> Result<(), MigrateError> {
+    let mut conn = db.acquire().await?;
+    conn.lock().await?;
+    let result = if migration.migration_type.is_down_migration() {
+        conn.revert(migration).await
+    } else {
+        conn.apply(migration).await
+    };
+    conn.unlock().await?;
+    result.map(|_| ())
+}
+
+async fn applied_versions(db: &PgPool) -> Result<Vec<i64>, MigrateError> {
+    let mut conn = db.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let mut versions: Vec<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_db::start_empty_postgres;
+    use bigdecimal::BigDecimal;
+
+    // applies the up migrations not applied yet, up to `version`
+    async fn migrate_to(db: &PgPool, version: i64) {
+        let applied = applied_versions(db).await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        for migration in MIGRATOR.iter().filter(|m| {
+            m.migration_type.is_up_migration()
+                && m.version <= version
+                && !applied.contains(&m.version)
+        }) {
+            conn.apply(migration).await.unwrap();
+        }
+    }
+
+    async fn pending(db: &PgPool) -> Vec<i64> {
+        status(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|m| !m.applied)
+            .map(|m| m.version)
+            .collect()
+    }
+
+    async fn advisory_locks(db: &PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM pg_locks WHERE locktype = 'advisory'")
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_status_down_redo() {
+        let (_container, db) = start_empty_postgres().await;
+        let last = MIGRATOR.iter().map(|m| m.version).max().unwrap();
+
+        assert_eq!(down(&db).await.unwrap(), None);
+        assert_eq!(redo(&db).await.unwrap(), None);
+        assert_eq!(pending(&db).await.len() as i64, last);
+
+        up(&db).await.unwrap();
+        assert!(pending(&db).await.is_empty());
+
+        assert_eq!(down(&db).await.unwrap(), Some(last));
+        assert_eq!(pending(&db).await, [last]);
+        // the one before the reverted migration, which stays pending
+        assert_eq!(redo(&db).await.unwrap(), Some(last - 1));
+        assert_eq!(pending(&db).await, [last]);
+        assert_eq!(advisory_locks(&db).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_migration_releases_lock() {
+        let (_container, db) = start_empty_postgres().await;
+        up(&db).await.unwrap();
+
+        // the down migration of 0014 drops it
+        sqlx::query("DROP INDEX orders_placing_idx")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(down(&db).await.is_err());
+        assert_eq!(advisory_locks(&db).await, 0);
+        assert!(redo(&db).await.is_err());
+        assert_eq!(advisory_locks(&db).await, 0);
+
+        // the up migration of 0014 creates it
+        sqlx::query("CREATE INDEX orders_placing_idx ON orders (id)")
+            .execute(&db)
+            .await
+            .unwrap();
+        down(&db).await.unwrap();
+        sqlx::query("CREATE TYPE order_status AS ENUM ('placing')")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(up(&db).await.is_err());
+        assert_eq!(advisory_locks(&db).await, 0);
+        assert_eq!(pending(&db).await, [14]);
+    }
+
+    #[tokio::test]
+    async fn test_constraints_after_legacy_transactions() {
+        let (_container, db) = start_empty_postgres().await;
+        migrate_to(&db, 2).await;
+        sqlx::raw_sql(
+            "INSERT INTO accounts (id, owner_name, balance) VALUES \
+                 (1, 'src', 100), (2, 'dst', 50), (3, 'deleted', 0); \
+             INSERT INTO transactions (id, amount, src_account_id, dst_account_id, tx_timestamp) \
+                 VALUES (1, 10, 1, 2, NOW()), (2, NULL, 1, 2, NOW()), (4, 5, 1, 3, NOW()), \
+                 (5, -3, 1, 2, NOW()); \
+             INSERT INTO transactions (id, src_account_id, dst_account_id, tx_timestamp) \
+                 VALUES (3, 1, 2, NOW()); \
+             DELETE FROM accounts WHERE id = 3;",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        migrate_to(&db, 3).await;
+
+        MIGRATOR.run(&db).await.unwrap();
+
+        let transactions: Vec<(i64, BigDecimal, i64, i64)> = sqlx::query_as(
+            "SELECT id, amount, src_account_id, dst_account_id FROM transactions ORDER BY id",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            transactions,
+            [
+                (1, BigDecimal::from(10), 1, 2),
+                // the negative transfer went the other way
+                (5, BigDecimal::from(3), 2, 1)
+            ]
+        );
+        // the ledger still matches the balances and its transfers leave the sources
+        let mismatched: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM accounts acc \
+             WHERE acc.balance <> (SELECT COALESCE(SUM(amount), 0) FROM ledger_entries \
+                                   WHERE account_id = acc.id)",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(mismatched, 0);
+        let debits: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT l.transaction_id, l.account_id FROM ledger_entries l \
+             WHERE l.amount < 0 ORDER BY l.transaction_id",
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(debits, [(1, 1), (5, 2)]);
+    }
+}
@@ -0,0 +1,60 @@
+//! Values that must not end up in logs, `{:?}` dumps or serialized configs
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Deserializes like `T`, but prints and serializes as `[REDACTED]`.
+/// The value is only reachable through [`Secret::expose`].
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let secret: Secret<String> = serde_json::from_str(r#""1111""#).unwrap();
+        assert_eq!(secret.expose(), "1111");
+        assert_eq!(format!("{secret:?} {secret}"), "[REDACTED] [REDACTED]");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), r#""[REDACTED]""#);
+    }
+}
@@ -0,0 +1,230 @@
+//! Reservation, shipment and purchase services: a purchase reserves the stock of a
+//! product and schedules its shipment, an order does the same for several products
+//! with one shipment per warehouse.
+//!
+//! The whole stock of a product is in one warehouse, `products.warehouse_id`; a product
+//! stocked in several warehouses would need the stock kept per product and warehouse.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+mod address;
+mod expiry;
+mod memory;
+mod order;
+mod pg;
+mod saga;
+
+pub use address::{Address, AddressField};
+pub use expiry::spawn_expiry_sweeper;
+pub use memory::{
+    InMemoryOrderLog, InMemoryReservationService, InMemorySagaLog, InMemoryShipmentService,
+};
+pub use order::{Fulfillment, Order, OrderItem, OrderLine, OrderLog, OrderRecord, OrderStatus};
+pub use pg::{PgOrderLog, PgPurchaseService, PgReservationService, PgSagaLog, PgShipmentService};
+pub use saga::{Saga, SagaLog, SagaPurchaseService, SagaStep};
+
+/// How long a reservation holds the stock unless the service is given another TTL
+pub const DEFAULT_RESERVATION_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Where the stock of a product is unless told otherwise, same as in migrations/
+pub const DEFAULT_WAREHOUSE_ID: i64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize, ToSchema)]
+pub struct Reservation {
+    pub id: i64,
+    pub product_id: i64,
+    pub quantity: i64,
+    pub status: ReservationStatus,
+    /// Where the stock is held
+    pub warehouse_id: i64,
+}
+
+/// `Held` becomes `Confirmed` or `Released`, or `Expired` once its TTL is over; the
+/// stock of released and expired reservations is given back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, ToSchema)]
+#[sqlx(type_name = "reservation_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReservationStatus {
+    Held,
+    Confirmed,
+    Released,
+    Expired,
+}
+
+/// Reservations leaving one warehouse together
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Shipment {
+    pub id: i64,
+    pub address: Address,
+    pub warehouse_id: i64,
+    pub reservation_ids: Vec<i64>,
+}
+
+impl AsRef<Address> for Shipment {
+    fn as_ref(&self) -> &Address {
+        &self.address
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize, ToSchema)]
+pub struct Purchase {
+    pub id: i64,
+    pub reservation_id: i64,
+    pub shipment_id: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReserveError {
+    #[error("No product with ID {id}")]
+    NoSuchProduct { id: i64 },
+    #[error("Asked {asked}, but available {available}")]
+    NotEnough { asked: i64, available: i64 },
+    #[error("Quantity must be positive, got {0}")]
+    InvalidQuantity(i64),
+    #[error("No reservation with ID {id}")]
+    NoSuchReservation { id: i64 },
+    #[error("Reservation {id} is {status:?}")]
+    NotHeld { id: i64, status: ReservationStatus },
+    #[error("Database error: {0}")]
+    Db(#[from] sqlx::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShipmentError {
+    #[error("Invalid {field}: {reason}")]
+    InvalidAddress { field: AddressField, reason: String },
+    #[error("Nothing to ship")]
+    NothingToShip,
+    #[error("Reservations of one shipment come from warehouses {warehouse_ids:?}")]
+    MixedWarehouses { warehouse_ids: Vec<i64> },
+    #[error("Reservations {reservation_ids:?} are already shipped")]
+    AlreadyShipped { reservation_ids: Vec<i64> },
+    #[error("No shipment with ID {id}")]
+    NoSuchShipment { id: i64 },
+    #[error("Database error: {0}")]
+    Db(#[from] sqlx::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PurchaseError {
+    #[error("Nested reservation error: {0}")]
+    ReservationFailed(#[from] ReserveError),
+    #[error("Nested shipping error: {0}")]
+    ShippingFailed(#[from] ShipmentError),
+    /// Found unfinished by the recovery, the text is the cause
+    #[error("Purchase interrupted: {0}")]
+    Interrupted(String),
+    #[error("Order has no items")]
+    EmptyOrder,
+    #[error("Database error: {0}")]
+    Db(#[from] sqlx::Error),
+}
+
+#[async_trait::async_trait]
+pub trait ReservationService: Send + Sync {
+    /// Stock not reserved
+    async fn available(&self, product_id: i64) -> Result<i64, ReserveError>;
+
+    async fn reservation(&self, reservation_id: i64) -> Result<Reservation, ReserveError>;
+
+    /// Holds the stock until the reservation is confirmed, released or expired
+    async fn reserve(&self, product_id: i64, quantity: i64) -> Result<Reservation, ReserveError>;
+
+    /// Keeps the stock for good, confirming a confirmed reservation again does nothing.
+    /// A held reservation past its TTL is `NotHeld` as `Expired` even before it's swept.
+    async fn confirm(&self, reservation_id: i64) -> Result<Reservation, ReserveError>;
+
+    /// Gives the stock back, releasing a released or expired reservation does nothing.
+    /// Confirmed reservations are released too, it's how their purchase or order is
+    /// compensated once the shipment is canceled.
+    async fn release(&self, reservation_id: i64) -> Result<(), ReserveError>;
+
+    /// Like [`release`](Self::release), but a confirmed reservation is `NotHeld`: it's
+    /// sold, and only compensating its purchase or order gives the stock back
+    async fn release_held(&self, reservation_id: i64) -> Result<(), ReserveError>;
+
+    /// Expires the held reservations past their TTL and gives their stock back,
+    /// returns how many expired
+    async fn expire_held(&self) -> Result<u64, ReserveError>;
+}
+
+#[async_trait::async_trait]
+pub trait ShipmentService: Send + Sync {
+    /// Ships the reservations of one warehouse, each of them is shipped once
+    async fn schedule_shipment(
+        &self,
+        reservations: &[Reservation],
+        address: &Address,
+    ) -> Result<Shipment, ShipmentError>;
+
+    /// The shipment the reservation is on, `None` if it isn't shipped or the shipment is
+    /// canceled
+    async fn shipment_of(&self, reservation_id: i64) -> Result<Option<Shipment>, ShipmentError>;
+
+    /// Takes the reservations off the shipment, so they can be shipped again or released.
+    /// Canceling a canceled shipment does nothing.
+    async fn cancel_shipment(&self, shipment_id: i64) -> Result<(), ShipmentError>;
+}
+
+#[async_trait::async_trait]
+pub trait PurchaseService: Send + Sync {
+    async fn purchase(
+        &self,
+        product_id: i64,
+        quantity: i64,
+        address: &Address,
+    ) -> Result<Purchase, PurchaseError>;
+
+    /// `None` if there is no such purchase or it hasn't completed
+    async fn find_purchase(&self, purchase_id: i64) -> Result<Option<Purchase>, PurchaseError>;
+
+    /// Reserves the items and ships them from their warehouses. With
+    /// [`Fulfillment::Partial`] the missing stock is backordered instead of failing the
+    /// whole order.
+    async fn place_order(
+        &self,
+        items: &[OrderItem],
+        address: &Address,
+        fulfillment: Fulfillment,
+    ) -> Result<Order, PurchaseError>;
+
+    /// `None` if there is no such order, failed ones included
+    async fn find_order(&self, order_id: i64) -> Result<Option<Order>, PurchaseError>;
+}
+
+fn check_quantity(quantity: i64) -> Result<(), ReserveError> {
+    if quantity <= 0 {
+        return Err(ReserveError::InvalidQuantity(quantity));
+    }
+    Ok(())
+}
+
+// the reservations of one shipment leave one warehouse
+fn check_shipment(reservations: &[Reservation]) -> Result<i64, ShipmentError> {
+    let first = reservations.first().ok_or(ShipmentError::NothingToShip)?;
+    if reservations
+        .iter()
+        .any(|r| r.warehouse_id != first.warehouse_id)
+    {
+        let mut warehouse_ids: Vec<i64> = reservations.iter().map(|r| r.warehouse_id).collect();
+        warehouse_ids.sort();
+        warehouse_ids.dedup();
+        return Err(ShipmentError::MixedWarehouses { warehouse_ids });
+    }
+    Ok(first.warehouse_id)
+}
+
+// an address loaded from storage skipped `Address::new`
+fn check_address(address: &Address) -> Result<(), ShipmentError> {
+    Address::new(
+        &address.street,
+        &address.city,
+        &address.postal_code,
+        &address.country,
+    )
+    .map(drop)
+}
@@ -0,0 +1,201 @@
+//! Shipping address with the postal code checked against the rules of its country
+
+use std::fmt;
+
+use serde::Serialize;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::ShipmentError;
+
+/// Countries we ship to and their postal code formats: `9` is a digit, `A` a letter,
+/// anything else is itself
+const POSTAL_CODE_FORMATS: &[(&str, &[&str])] = &[
+    ("CA", &["A9A 9A9"]),
+    ("DE", &["99999"]),
+    ("FR", &["99999"]),
+    (
+        "GB",
+        &[
+            "A9 9AA", "A99 9AA", "A9A 9AA", "AA9 9AA", "AA99 9AA", "AA9A 9AA",
+        ],
+    ),
+    ("MD", &["MD-9999", "9999"]),
+    ("NL", &["9999 AA"]),
+    ("RO", &["999999"]),
+    ("US", &["99999", "99999-9999"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressField {
+    Street,
+    City,
+    PostalCode,
+    Country,
+}
+
+/// Valid when built by [`Address::new`]: no empty field, a supported ISO 3166 country
+/// code and a postal code of its format. Country and postal code are kept uppercase.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize, ToSchema)]
+pub struct Address {
+    pub(super) street: String,
+    pub(super) city: String,
+    pub(super) postal_code: String,
+    pub(super) country: String,
+}
+
+impl Address {
+    pub fn new(
+        street: &str,
+        city: &str,
+        postal_code: &str,
+        country: &str,
+    ) -> Result<Address, ShipmentError> {
+        let street = non_empty(AddressField::Street, street)?;
+        let city = non_empty(AddressField::City, city)?;
+        let postal_code = non_empty(AddressField::PostalCode, postal_code)?.to_uppercase();
+        let country = non_empty(AddressField::Country, country)?.to_uppercase();
+
+        let formats = POSTAL_CODE_FORMATS
+            .iter()
+            .find(|(code, _)| *code == country)
+            .map(|(_, formats)| *formats)
+            .ok_or_else(|| invalid(AddressField::Country, format!("cannot ship to {country:?}")))?;
+        if !formats
+            .iter()
+            .any(|format| matches_format(&postal_code, format))
+        {
+            return Err(invalid(
+                AddressField::PostalCode,
+                format!(
+                    "{postal_code:?} is not one of {} in {country}",
+                    formats.join(", ")
+                ),
+            ));
+        }
+
+        Ok(Address {
+            street: street.to_string(),
+            city: city.to_string(),
+            postal_code,
+            country,
+        })
+    }
+
+    pub fn street(&self) -> &str {
+        &self.street
+    }
+
+    pub fn city(&self) -> &str {
+        &self.city
+    }
+
+    pub fn postal_code(&self) -> &str {
+        &self.postal_code
+    }
+
+    pub fn country(&self) -> &str {
+        &self.country
+    }
+
+    /// Skips the checks, like an address loaded from storage
+    #[cfg(test)]
+    pub(super) fn unchecked(street: &str, city: &str, postal_code: &str, country: &str) -> Self {
+        Address {
+            street: street.to_string(),
+            city: city.to_string(),
+            postal_code: postal_code.to_string(),
+            country: country.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} {}, {}",
+            self.street, self.postal_code, self.city, self.country
+        )
+    }
+}
+
+impl fmt::Display for AddressField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressField::Street => "street",
+            AddressField::City => "city",
+            AddressField::PostalCode => "postal code",
+            AddressField::Country => "country",
+        })
+    }
+}
+
+fn non_empty(field: AddressField, value: &str) -> Result<&str, ShipmentError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(invalid(field, "cannot be empty".into()));
+    }
+    Ok(value)
+}
+
+fn invalid(field: AddressField, reason: String) -> ShipmentError {
+    ShipmentError::InvalidAddress { field, reason }
+}
+
+fn matches_format(postal_code: &str, format: &str) -> bool {
+    postal_code.chars().count() == format.chars().count()
+        && postal_code
+            .chars()
+            .zip(format.chars())
+            .all(|(c, f)| match f {
+                '9' => c.is_ascii_digit(),
+                'A' => c.is_ascii_uppercase(),
+                f => c == f,
+            })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn invalid_field(result: Result<Address, ShipmentError>) -> Option<AddressField> {
+        match result {
+            Err(ShipmentError::InvalidAddress { field, .. }) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        let address = Address::new(" Stefan cel Mare 1 ", "Chisinau", "md-2001", "md").unwrap();
+        assert_eq!(address.postal_code(), "MD-2001");
+        assert_eq!(
+            address.to_string(),
+            "Stefan cel Mare 1, MD-2001 Chisinau, MD"
+        );
+        Address::new("10 Downing St", "London", "SW1A 2AA", "GB").unwrap();
+        Address::new("1 Main St", "Springfield", "12345-6789", "US").unwrap();
+
+        assert_eq!(
+            invalid_field(Address::new(" ", "Chisinau", "2001", "MD")),
+            Some(AddressField::Street)
+        );
+        assert_eq!(
+            invalid_field(Address::new("Main 1", "", "2001", "MD")),
+            Some(AddressField::City)
+        );
+        assert_eq!(
+            invalid_field(Address::new("Main 1", "Chisinau", "2001", "XX")),
+            Some(AddressField::Country)
+        );
+        assert_eq!(
+            invalid_field(Address::new("Main 1", "Berlin", "1011", "DE")),
+            Some(AddressField::PostalCode)
+        );
+        assert_eq!(
+            invalid_field(Address::new("Main 1", "Toronto", "M5V 3L9 ", "CA")),
+            None
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Background task giving the stock of expired reservations back
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+use super::ReservationService;
+
+/// Expires the held reservations past their TTL every `interval`, starting right away.
+/// Runs until the handle is aborted, a failed sweep is logged and retried on the next tick.
+pub fn spawn_expiry_sweeper(
+    reservation_service: Arc<dyn ReservationService>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            match reservation_service.expire_held().await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("Expired {expired} reservations"),
+                Err(e) => tracing::error!("Cannot expire reservations: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shop::{InMemoryReservationService, ReservationStatus, ReserveError};
+    use std::collections::HashMap;
+
+    const TTL: Duration = Duration::from_secs(60);
+    const INTERVAL: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sweeper_expires_held_reservations() {
+        let reservations =
+            Arc::new(InMemoryReservationService::new(HashMap::from([(111, 50)])).with_ttl(TTL));
+        let sweeper = spawn_expiry_sweeper(reservations.clone(), INTERVAL);
+
+        let held = reservations.reserve(111, 10).await.unwrap();
+        let confirmed = reservations.reserve(111, 5).await.unwrap();
+        let confirmed = reservations.confirm(confirmed.id).await.unwrap();
+        assert_eq!(confirmed.status, ReservationStatus::Confirmed);
+        assert_eq!(reservations.stock(111), Some(35));
+
+        tokio::time::sleep(TTL - INTERVAL).await;
+        assert_eq!(reservations.stock(111), Some(35));
+
+        // the first tick past the TTL gives the held stock back
+        tokio::time::sleep(INTERVAL * 2).await;
+        assert_eq!(reservations.stock(111), Some(45));
+        assert!(matches!(
+            reservations.confirm(held.id).await,
+            Err(ReserveError::NotHeld {
+                status: ReservationStatus::Expired,
+                ..
+            })
+        ));
+        // expired stock is given back once
+        reservations.release(held.id).await.unwrap();
+        assert_eq!(reservations.stock(111), Some(45));
+
+        sweeper.abort();
+    }
+}
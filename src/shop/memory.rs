@@ -0,0 +1,413 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use super::{
+    check_address, check_quantity, check_shipment, Address, Fulfillment, OrderLine, OrderLog,
+    OrderRecord, OrderStatus, Reservation, ReservationService, ReservationStatus, ReserveError,
+    Saga, SagaLog, SagaStep, Shipment, ShipmentError, ShipmentService, DEFAULT_RESERVATION_TTL,
+    DEFAULT_WAREHOUSE_ID,
+};
+
+// same as the sequences in migrations/
+const FIRST_ID: i64 = 1000;
+
+/// Stock by product ID, lost on exit. Products are in [`DEFAULT_WAREHOUSE_ID`] unless
+/// placed elsewhere. Expiry follows the tokio clock, so it can be tested with paused time.
+pub struct InMemoryReservationService {
+    state: Mutex<ReservationState>,
+    next_id: AtomicI64,
+    ttl: Duration,
+}
+
+struct ReservationState {
+    stock: HashMap<i64, i64>,
+    // product ID -> warehouse ID, if not the default one
+    warehouses: HashMap<i64, i64>,
+    // reservation ID -> reservation and when it expires while held
+    reservations: HashMap<i64, (Reservation, Instant)>,
+}
+
+impl ReservationState {
+    fn give_back(&mut self, product_id: i64, quantity: i64) {
+        *self.stock.entry(product_id).or_default() += quantity;
+    }
+
+    fn release(&mut self, reservation_id: i64, confirmed_too: bool) -> Result<(), ReserveError> {
+        let (reservation, _) = self
+            .reservations
+            .get_mut(&reservation_id)
+            .ok_or(ReserveError::NoSuchReservation { id: reservation_id })?;
+        match reservation.status {
+            ReservationStatus::Released | ReservationStatus::Expired => return Ok(()),
+            ReservationStatus::Confirmed if !confirmed_too => {
+                return Err(ReserveError::NotHeld {
+                    id: reservation_id,
+                    status: ReservationStatus::Confirmed,
+                })
+            }
+            _ => {}
+        }
+        reservation.status = ReservationStatus::Released;
+        let (product_id, quantity) = (reservation.product_id, reservation.quantity);
+        self.give_back(product_id, quantity);
+        Ok(())
+    }
+}
+
+impl InMemoryReservationService {
+    pub fn new(stock: HashMap<i64, i64>) -> Self {
+        InMemoryReservationService {
+            state: Mutex::new(ReservationState {
+                stock,
+                warehouses: HashMap::new(),
+                reservations: HashMap::new(),
+            }),
+            next_id: AtomicI64::new(FIRST_ID),
+            ttl: DEFAULT_RESERVATION_TTL,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Keeps the stock of `product_id` in `warehouse_id`
+    pub fn with_warehouse(self, product_id: i64, warehouse_id: i64) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .warehouses
+            .insert(product_id, warehouse_id);
+        self
+    }
+
+    pub fn stock(&self, product_id: i64) -> Option<i64> {
+        self.state.lock().unwrap().stock.get(&product_id).copied()
+    }
+}
+
+#[async_trait::async_trait]
+impl ReservationService for InMemoryReservationService {
+    async fn available(&self, product_id: i64) -> Result<i64, ReserveError> {
+        self.stock(product_id)
+            .ok_or(ReserveError::NoSuchProduct { id: product_id })
+    }
+
+    async fn reservation(&self, reservation_id: i64) -> Result<Reservation, ReserveError> {
+        let state = self.state.lock().unwrap();
+        let (reservation, _) = state
+            .reservations
+            .get(&reservation_id)
+            .ok_or(ReserveError::NoSuchReservation { id: reservation_id })?;
+        Ok(reservation.clone())
+    }
+
+    async fn reserve(&self, product_id: i64, quantity: i64) -> Result<Reservation, ReserveError> {
+        check_quantity(quantity)?;
+        let mut state = self.state.lock().unwrap();
+        let stock = state
+            .stock
+            .get_mut(&product_id)
+            .ok_or(ReserveError::NoSuchProduct { id: product_id })?;
+        if *stock < quantity {
+            return Err(ReserveError::NotEnough {
+                asked: quantity,
+                available: *stock,
+            });
+        }
+        *stock -= quantity;
+        let reservation = Reservation {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            product_id,
+            quantity,
+            status: ReservationStatus::Held,
+            warehouse_id: state
+                .warehouses
+                .get(&product_id)
+                .copied()
+                .unwrap_or(DEFAULT_WAREHOUSE_ID),
+        };
+        state.reservations.insert(
+            reservation.id,
+            (reservation.clone(), Instant::now() + self.ttl),
+        );
+        Ok(reservation)
+    }
+
+    async fn confirm(&self, reservation_id: i64) -> Result<Reservation, ReserveError> {
+        let mut state = self.state.lock().unwrap();
+        let (reservation, expires_at) = state
+            .reservations
+            .get_mut(&reservation_id)
+            .ok_or(ReserveError::NoSuchReservation { id: reservation_id })?;
+        match reservation.status {
+            ReservationStatus::Held if *expires_at > Instant::now() => {
+                reservation.status = ReservationStatus::Confirmed;
+                Ok(reservation.clone())
+            }
+            ReservationStatus::Held => Err(ReserveError::NotHeld {
+                id: reservation_id,
+                status: ReservationStatus::Expired,
+            }),
+            ReservationStatus::Confirmed => Ok(reservation.clone()),
+            status => Err(ReserveError::NotHeld {
+                id: reservation_id,
+                status,
+            }),
+        }
+    }
+
+    async fn release(&self, reservation_id: i64) -> Result<(), ReserveError> {
+        self.state.lock().unwrap().release(reservation_id, true)
+    }
+
+    async fn release_held(&self, reservation_id: i64) -> Result<(), ReserveError> {
+        self.state.lock().unwrap().release(reservation_id, false)
+    }
+
+    async fn expire_held(&self) -> Result<u64, ReserveError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (reservation, expires_at) in state.reservations.values_mut() {
+            if reservation.status == ReservationStatus::Held && *expires_at <= now {
+                reservation.status = ReservationStatus::Expired;
+                expired.push((reservation.product_id, reservation.quantity));
+            }
+        }
+        for &(product_id, quantity) in &expired {
+            state.give_back(product_id, quantity);
+        }
+        Ok(expired.len() as u64)
+    }
+}
+
+pub struct InMemoryShipmentService {
+    next_id: AtomicI64,
+    state: Mutex<ShipmentState>,
+}
+
+#[derive(Default)]
+struct ShipmentState {
+    shipments: HashMap<i64, Shipment>,
+    // reservation ID -> ID of the shipment it's on, until that is canceled
+    shipped: HashMap<i64, i64>,
+}
+
+impl InMemoryShipmentService {
+    pub fn new() -> Self {
+        InMemoryShipmentService {
+            next_id: AtomicI64::new(FIRST_ID),
+            state: Mutex::new(ShipmentState::default()),
+        }
+    }
+}
+
+impl Default for InMemoryShipmentService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl ShipmentService for InMemoryShipmentService {
+    async fn schedule_shipment(
+        &self,
+        reservations: &[Reservation],
+        address: &Address,
+    ) -> Result<Shipment, ShipmentError> {
+        check_address(address)?;
+        let warehouse_id = check_shipment(reservations)?;
+        let reservation_ids: Vec<i64> = reservations.iter().map(|r| r.id).collect();
+
+        let mut state = self.state.lock().unwrap();
+        let already_shipped: Vec<i64> = reservation_ids
+            .iter()
+            .copied()
+            .filter(|id| state.shipped.contains_key(id))
+            .collect();
+        if !already_shipped.is_empty() {
+            return Err(ShipmentError::AlreadyShipped {
+                reservation_ids: already_shipped,
+            });
+        }
+        let shipment = Shipment {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            address: address.clone(),
+            warehouse_id,
+            reservation_ids,
+        };
+        for &reservation_id in &shipment.reservation_ids {
+            state.shipped.insert(reservation_id, shipment.id);
+        }
+        state.shipments.insert(shipment.id, shipment.clone());
+        Ok(shipment)
+    }
+
+    async fn shipment_of(&self, reservation_id: i64) -> Result<Option<Shipment>, ShipmentError> {
+        let state = self.state.lock().unwrap();
+        let Some(shipment_id) = state.shipped.get(&reservation_id) else {
+            return Ok(None);
+        };
+        Ok(state.shipments.get(shipment_id).cloned())
+    }
+
+    async fn cancel_shipment(&self, shipment_id: i64) -> Result<(), ShipmentError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.shipments.contains_key(&shipment_id) {
+            return Err(ShipmentError::NoSuchShipment { id: shipment_id });
+        }
+        state.shipped.retain(|_, id| *id != shipment_id);
+        Ok(())
+    }
+}
+
+pub struct InMemorySagaLog {
+    // (saga ID, step) in the order of appending
+    steps: Mutex<Vec<(i64, SagaStep)>>,
+    next_id: AtomicI64,
+}
+
+impl InMemorySagaLog {
+    pub fn new() -> Self {
+        InMemorySagaLog {
+            steps: Mutex::new(Vec::new()),
+            next_id: AtomicI64::new(FIRST_ID),
+        }
+    }
+}
+
+impl Default for InMemorySagaLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl SagaLog for InMemorySagaLog {
+    async fn start(
+        &self,
+        product_id: i64,
+        quantity: i64,
+        address: &Address,
+    ) -> Result<i64, sqlx::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let step = SagaStep::Started {
+            product_id,
+            quantity,
+            address: address.clone(),
+        };
+        self.steps.lock().unwrap().push((id, step));
+        Ok(id)
+    }
+
+    async fn append(&self, saga_id: i64, step: &SagaStep) -> Result<(), sqlx::Error> {
+        self.steps.lock().unwrap().push((saga_id, step.clone()));
+        Ok(())
+    }
+
+    async fn saga(&self, saga_id: i64) -> Result<Option<Saga>, sqlx::Error> {
+        let steps: Vec<SagaStep> = self
+            .steps
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| *id == saga_id)
+            .map(|(_, step)| step.clone())
+            .collect();
+        Ok(Saga::from_steps(saga_id, steps))
+    }
+
+    async fn unfinished(&self) -> Result<Vec<Saga>, sqlx::Error> {
+        let mut steps_by_saga: BTreeMap<i64, Vec<SagaStep>> = BTreeMap::new();
+        for (saga_id, step) in self.steps.lock().unwrap().iter() {
+            steps_by_saga
+                .entry(*saga_id)
+                .or_default()
+                .push(step.clone());
+        }
+        Ok(steps_by_saga
+            .into_iter()
+            .filter_map(|(id, steps)| Saga::from_steps(id, steps))
+            .filter(|saga| !saga.last_step.is_final())
+            .collect())
+    }
+}
+
+pub struct InMemoryOrderLog {
+    orders: Mutex<BTreeMap<i64, OrderRecord>>,
+    next_id: AtomicI64,
+}
+
+impl InMemoryOrderLog {
+    pub fn new() -> Self {
+        InMemoryOrderLog {
+            orders: Mutex::new(BTreeMap::new()),
+            next_id: AtomicI64::new(FIRST_ID),
+        }
+    }
+}
+
+impl Default for InMemoryOrderLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl OrderLog for InMemoryOrderLog {
+    // the address is only kept by the shipments
+    async fn start(
+        &self,
+        fulfillment: Fulfillment,
+        _address: &Address,
+    ) -> Result<i64, sqlx::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let order = OrderRecord {
+            id,
+            fulfillment,
+            status: OrderStatus::Placing,
+            lines: Vec::new(),
+        };
+        self.orders.lock().unwrap().insert(id, order);
+        Ok(id)
+    }
+
+    async fn add_line(&self, order_id: i64, line: &OrderLine) -> Result<(), sqlx::Error> {
+        let mut orders = self.orders.lock().unwrap();
+        let order = orders.get_mut(&order_id).ok_or(sqlx::Error::RowNotFound)?;
+        order.lines.push(line.clone());
+        Ok(())
+    }
+
+    async fn set_status(&self, order_id: i64, status: OrderStatus) -> Result<(), sqlx::Error> {
+        let mut orders = self.orders.lock().unwrap();
+        let order = orders.get_mut(&order_id).ok_or(sqlx::Error::RowNotFound)?;
+        order.status = status;
+        Ok(())
+    }
+
+    async fn order(&self, order_id: i64) -> Result<Option<OrderRecord>, sqlx::Error> {
+        Ok(self.orders.lock().unwrap().get(&order_id).cloned())
+    }
+
+    async fn unfinished(&self) -> Result<Vec<OrderRecord>, sqlx::Error> {
+        Ok(self
+            .orders
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|order| order.status == OrderStatus::Placing)
+            .cloned()
+            .collect())
+    }
+}
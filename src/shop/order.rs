@@ -0,0 +1,446 @@
+//! Orders of several products: the items are reserved one by one, then every warehouse
+//! ships its part and the reservations are confirmed. The order and its lines are
+//! logged as they're placed, orders interrupted by a crash are compensated by [`recover`].
+//!
+//! A reservation made right before a crash is not logged yet and stays held until it
+//! expires. The shipments don't need logging, they're found through the reservations.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use super::{
+    Address, PurchaseError, Reservation, ReservationService, ReserveError, Shipment,
+    ShipmentService,
+};
+
+/// `Placing` until every item is reserved, shipped and confirmed, `Failed` once whatever
+/// was reserved and shipped is given back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "order_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Placing,
+    Placed,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, ToSchema)]
+pub struct OrderItem {
+    pub product_id: i64,
+    pub quantity: i64,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema, sqlx::Type,
+)]
+#[sqlx(type_name = "order_fulfillment", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Fulfillment {
+    /// Every item is reserved in full, or the order fails and nothing stays reserved
+    #[default]
+    AllOrNothing,
+    /// Items are reserved as far as the stock goes and the rest is backordered
+    Partial,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema, FromRow)]
+pub struct OrderLine {
+    pub product_id: i64,
+    pub ordered: i64,
+    /// `None` when nothing was in stock
+    pub reservation_id: Option<i64>,
+    pub reserved: i64,
+    /// Left to be fulfilled later
+    pub backordered: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Order {
+    pub id: i64,
+    pub fulfillment: Fulfillment,
+    pub status: OrderStatus,
+    pub lines: Vec<OrderLine>,
+    /// One per warehouse, none when everything is backordered
+    pub shipments: Vec<Shipment>,
+}
+
+/// An order as logged, without its shipments
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderRecord {
+    pub id: i64,
+    pub fulfillment: Fulfillment,
+    pub status: OrderStatus,
+    pub lines: Vec<OrderLine>,
+}
+
+/// Orders and their lines, written while the orders are placed
+#[async_trait::async_trait]
+pub trait OrderLog: Send + Sync {
+    /// Records a new `Placing` order and returns its ID
+    async fn start(&self, fulfillment: Fulfillment, address: &Address) -> Result<i64, sqlx::Error>;
+
+    /// Records a line once its item is reserved, or found out of stock
+    async fn add_line(&self, order_id: i64, line: &OrderLine) -> Result<(), sqlx::Error>;
+
+    async fn set_status(&self, order_id: i64, status: OrderStatus) -> Result<(), sqlx::Error>;
+
+    /// `None` if there is no order with the ID
+    async fn order(&self, order_id: i64) -> Result<Option<OrderRecord>, sqlx::Error>;
+
+    /// Orders still `Placing`
+    async fn unfinished(&self) -> Result<Vec<OrderRecord>, sqlx::Error>;
+}
+
+impl OrderLine {
+    pub(super) fn new(item: &OrderItem, reservation: Option<&Reservation>) -> OrderLine {
+        let reserved = reservation.map_or(0, |r| r.quantity);
+        OrderLine {
+            product_id: item.product_id,
+            ordered: item.quantity,
+            reservation_id: reservation.map(|r| r.id),
+            reserved,
+            backordered: item.quantity - reserved,
+        }
+    }
+}
+
+pub(super) fn check_items(items: &[OrderItem]) -> Result<(), PurchaseError> {
+    if items.is_empty() {
+        return Err(PurchaseError::EmptyOrder);
+    }
+    Ok(())
+}
+
+/// Reservations grouped by warehouse, in the order of the warehouse IDs
+pub(super) fn by_warehouse(reservations: &[Reservation]) -> Vec<Vec<Reservation>> {
+    let mut groups: BTreeMap<i64, Vec<Reservation>> = BTreeMap::new();
+    for reservation in reservations {
+        groups
+            .entry(reservation.warehouse_id)
+            .or_default()
+            .push(reservation.clone());
+    }
+    groups.into_values().collect()
+}
+
+/// The quantity to reserve after `error`, `None` if the item has to fail
+pub(super) fn partial_quantity(fulfillment: Fulfillment, error: &ReserveError) -> Option<i64> {
+    match (fulfillment, error) {
+        (Fulfillment::Partial, ReserveError::NotEnough { available, .. }) => Some(*available),
+        _ => None,
+    }
+}
+
+/// Places the order over services that don't share a transaction: when a step fails,
+/// the order is compensated. The caller checks the items.
+pub(super) async fn place_order(
+    reservation_service: &dyn ReservationService,
+    shipment_service: &dyn ShipmentService,
+    log: &dyn OrderLog,
+    items: &[OrderItem],
+    address: &Address,
+    fulfillment: Fulfillment,
+) -> Result<Order, PurchaseError> {
+    let id = log.start(fulfillment, address).await?;
+    let mut reservations = Vec::new();
+    let placed = async {
+        let mut lines = Vec::new();
+        for item in items {
+            let reservation = reserve_item(reservation_service, item, fulfillment).await?;
+            let line = OrderLine::new(item, reservation.as_ref());
+            reservations.extend(reservation);
+            log.add_line(id, &line).await?;
+            lines.push(line);
+        }
+        let mut shipments = Vec::new();
+        for group in by_warehouse(&reservations) {
+            shipments.push(shipment_service.schedule_shipment(&group, address).await?);
+        }
+        for reservation in &reservations {
+            reservation_service.confirm(reservation.id).await?;
+        }
+        log.set_status(id, OrderStatus::Placed).await?;
+        Ok::<_, PurchaseError>((lines, shipments))
+    }
+    .await;
+
+    match placed {
+        Ok((lines, shipments)) => Ok(Order {
+            id,
+            fulfillment,
+            status: OrderStatus::Placed,
+            lines,
+            shipments,
+        }),
+        Err(e) => {
+            let reservation_ids: Vec<i64> = reservations.iter().map(|r| r.id).collect();
+            let compensated = compensate(
+                reservation_service,
+                shipment_service,
+                log,
+                id,
+                &reservation_ids,
+            )
+            .await;
+            if let Err(compensation_error) = compensated {
+                // the recovery tries again
+                tracing::warn!("Cannot compensate order {id}: {compensation_error}");
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Compensates the orders interrupted by a crash, they're not resumed
+pub(super) async fn recover(
+    reservation_service: &dyn ReservationService,
+    shipment_service: &dyn ShipmentService,
+    log: &dyn OrderLog,
+) -> Result<Vec<(i64, Result<(), PurchaseError>)>, sqlx::Error> {
+    let mut recovered = Vec::new();
+    for order in log.unfinished().await? {
+        let reservation_ids: Vec<i64> = order
+            .lines
+            .iter()
+            .filter_map(|line| line.reservation_id)
+            .collect();
+        let outcome = compensate(
+            reservation_service,
+            shipment_service,
+            log,
+            order.id,
+            &reservation_ids,
+        )
+        .await;
+        tracing::info!("Recovered order {}: {:?}", order.id, outcome);
+        recovered.push((order.id, outcome));
+    }
+    Ok(recovered)
+}
+
+/// The logged order with the shipments of its reservations
+pub(super) async fn find_order(
+    shipment_service: &dyn ShipmentService,
+    log: &dyn OrderLog,
+    order_id: i64,
+) -> Result<Option<Order>, PurchaseError> {
+    let Some(order) = log.order(order_id).await? else {
+        return Ok(None);
+    };
+    let mut shipments: Vec<Shipment> = Vec::new();
+    for reservation_id in order.lines.iter().filter_map(|line| line.reservation_id) {
+        let Some(shipment) = shipment_service.shipment_of(reservation_id).await? else {
+            continue;
+        };
+        if shipments.iter().all(|s| s.id != shipment.id) {
+            shipments.push(shipment);
+        }
+    }
+    shipments.sort_by_key(|s| s.warehouse_id);
+    Ok(Some(Order {
+        id: order.id,
+        fulfillment: order.fulfillment,
+        status: order.status,
+        lines: order.lines,
+        shipments,
+    }))
+}
+
+/// Cancels the shipments of the reservations, releases them and marks the order
+/// `Failed`. A failed step leaves the order `Placing`, so it's compensated again.
+async fn compensate(
+    reservation_service: &dyn ReservationService,
+    shipment_service: &dyn ShipmentService,
+    log: &dyn OrderLog,
+    order_id: i64,
+    reservation_ids: &[i64],
+) -> Result<(), PurchaseError> {
+    for &reservation_id in reservation_ids {
+        // a shipment of several reservations is canceled with the first one
+        if let Some(shipment) = shipment_service.shipment_of(reservation_id).await? {
+            shipment_service.cancel_shipment(shipment.id).await?;
+        }
+        reservation_service.release(reservation_id).await?;
+    }
+    log.set_status(order_id, OrderStatus::Failed).await?;
+    Ok(())
+}
+
+async fn reserve_item(
+    reservation_service: &dyn ReservationService,
+    item: &OrderItem,
+    fulfillment: Fulfillment,
+) -> Result<Option<Reservation>, ReserveError> {
+    let mut quantity = item.quantity;
+    loop {
+        match reservation_service.reserve(item.product_id, quantity).await {
+            Ok(reservation) => return Ok(Some(reservation)),
+            // the stock may be taken meanwhile, then it's retried with less
+            Err(e) => match partial_quantity(fulfillment, &e) {
+                Some(0) => return Ok(None),
+                Some(available) => quantity = available,
+                None => return Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shop::{
+        InMemoryOrderLog, InMemoryReservationService, InMemoryShipmentService, ShipmentError,
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    fn items(quantities: &[(i64, i64)]) -> Vec<OrderItem> {
+        quantities
+            .iter()
+            .map(|&(product_id, quantity)| OrderItem {
+                product_id,
+                quantity,
+            })
+            .collect()
+    }
+
+    fn address() -> Address {
+        Address::new("Stefan cel Mare 1", "Chisinau", "MD-2001", "MD").unwrap()
+    }
+
+    fn reservations() -> InMemoryReservationService {
+        InMemoryReservationService::new(HashMap::from([(111, 50), (222, 5)]))
+            .with_warehouse(222, 1001)
+    }
+
+    async fn status(log: &InMemoryOrderLog, order_id: i64) -> OrderStatus {
+        log.order(order_id).await.unwrap().unwrap().status
+    }
+
+    #[tokio::test]
+    async fn test_place_order() {
+        let reservations = reservations();
+        let shipments = InMemoryShipmentService::new();
+        let log = InMemoryOrderLog::new();
+        let place = |items: Vec<OrderItem>, address: Address, fulfillment| {
+            let (reservations, shipments, log) = (&reservations, &shipments, &log);
+            async move { place_order(reservations, shipments, log, &items, &address, fulfillment).await }
+        };
+
+        // the first item is released with the second
+        let order = items(&[(111, 10), (222, 8)]);
+        assert!(matches!(
+            place(order.clone(), address(), Fulfillment::AllOrNothing).await,
+            Err(PurchaseError::ReservationFailed(ReserveError::NotEnough {
+                asked: 8,
+                available: 5
+            }))
+        ));
+        assert_eq!(reservations.stock(111), Some(50));
+        let invalid = Address::unchecked("Stefan cel Mare 1", "Chisinau", "2001-MD", "MD");
+        assert!(matches!(
+            place(order.clone(), invalid, Fulfillment::Partial).await,
+            Err(PurchaseError::ShippingFailed(
+                ShipmentError::InvalidAddress { .. }
+            ))
+        ));
+        assert_eq!(reservations.stock(111), Some(50));
+        assert_eq!(reservations.stock(222), Some(5));
+        assert_eq!(status(&log, 1000).await, OrderStatus::Failed);
+        assert_eq!(status(&log, 1001).await, OrderStatus::Failed);
+
+        let placed = place(order, address(), Fulfillment::Partial).await.unwrap();
+        let backordered: Vec<(i64, i64)> = placed
+            .lines
+            .iter()
+            .map(|line| (line.reserved, line.backordered))
+            .collect();
+        assert_eq!(backordered, [(10, 0), (5, 3)]);
+        let warehouses: Vec<i64> = placed.shipments.iter().map(|s| s.warehouse_id).collect();
+        assert_eq!(warehouses, [1000, 1001]);
+        assert_eq!(reservations.stock(111), Some(40));
+        assert_eq!(reservations.stock(222), Some(0));
+        // the backorders are kept with the order
+        assert_eq!(
+            find_order(&shipments, &log, placed.id).await.unwrap(),
+            Some(placed)
+        );
+
+        // nothing left to reserve, nothing to ship
+        let placed = place(items(&[(222, 1)]), address(), Fulfillment::Partial)
+            .await
+            .unwrap();
+        assert_eq!(placed.lines[0].reservation_id, None);
+        assert!(placed.shipments.is_empty());
+        assert_eq!(find_order(&shipments, &log, -1).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_failed_order_cancels_shipments() {
+        // the reservations expire before they're confirmed
+        let reservations = reservations().with_ttl(Duration::ZERO);
+        let shipments = InMemoryShipmentService::new();
+        let log = InMemoryOrderLog::new();
+
+        let order = items(&[(111, 10), (222, 5)]);
+        let placed = place_order(
+            &reservations,
+            &shipments,
+            &log,
+            &order,
+            &address(),
+            Fulfillment::AllOrNothing,
+        )
+        .await;
+        assert!(matches!(
+            placed,
+            Err(PurchaseError::ReservationFailed(
+                ReserveError::NotHeld { .. }
+            ))
+        ));
+        assert_eq!(reservations.stock(111), Some(50));
+        assert_eq!(reservations.stock(222), Some(5));
+        // both shipments were scheduled and are canceled
+        let failed = find_order(&shipments, &log, 1000).await.unwrap().unwrap();
+        assert_eq!(failed.status, OrderStatus::Failed);
+        assert!(failed.shipments.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let reservations = reservations();
+        let shipments = InMemoryShipmentService::new();
+        let log = InMemoryOrderLog::new();
+
+        // crashed after the shipment, before the confirmation
+        let order_id = log.start(Fulfillment::Partial, &address()).await.unwrap();
+        for item in items(&[(111, 10), (222, 8)]) {
+            let reservation = reserve_item(&reservations, &item, Fulfillment::Partial)
+                .await
+                .unwrap();
+            let line = OrderLine::new(&item, reservation.as_ref());
+            log.add_line(order_id, &line).await.unwrap();
+        }
+        let reservation = reservations.reservation(1000).await.unwrap();
+        shipments
+            .schedule_shipment(&[reservation], &address())
+            .await
+            .unwrap();
+        assert_eq!(reservations.stock(111), Some(40));
+
+        let recovered = recover(&reservations, &shipments, &log).await.unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert!(recovered[0].1.is_ok());
+        assert_eq!(reservations.stock(111), Some(50));
+        assert_eq!(reservations.stock(222), Some(5));
+        let order = find_order(&shipments, &log, order_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(order.status, OrderStatus::Failed);
+        assert!(order.shipments.is_empty());
+        assert!(log.unfinished().await.unwrap().is_empty());
+    }
+}
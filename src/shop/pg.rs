@@ -0,0 +1,1128 @@
+use std::time::Duration;
+
+use sqlx::{FromRow, PgConnection, PgPool};
+
+use super::{
+    check_address, check_quantity, check_shipment,
+    order::{self, by_warehouse, check_items, partial_quantity},
+    Address, Fulfillment, Order, OrderItem, OrderLine, OrderLog, OrderRecord, OrderStatus,
+    Purchase, PurchaseError, PurchaseService, Reservation, ReservationService, ReservationStatus,
+    ReserveError, Saga, SagaLog, SagaStep, Shipment, ShipmentError, ShipmentService,
+    DEFAULT_RESERVATION_TTL,
+};
+
+/// Stock of the `products` table. Expiry follows the clock of the database.
+pub struct PgReservationService {
+    db: PgPool,
+    ttl: Duration,
+}
+
+impl PgReservationService {
+    pub fn new(db: PgPool) -> Self {
+        PgReservationService {
+            db,
+            ttl: DEFAULT_RESERVATION_TTL,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Takes the stock and records the held reservation in the caller's transaction
+    pub async fn reserve_in(
+        conn: &mut PgConnection,
+        product_id: i64,
+        quantity: i64,
+        ttl: Duration,
+    ) -> Result<Reservation, ReserveError> {
+        check_quantity(quantity)?;
+        // the row lock of the UPDATE keeps concurrent reservations from overselling
+        let warehouse_id: Option<i64> = sqlx::query_scalar(
+            "UPDATE products SET stock = stock - $2 WHERE id = $1 AND stock >= $2 \
+             RETURNING warehouse_id",
+        )
+        .bind(product_id)
+        .bind(quantity)
+        .fetch_optional(&mut *conn)
+        .await?;
+        let Some(warehouse_id) = warehouse_id else {
+            let available: Option<i64> =
+                sqlx::query_scalar("SELECT stock FROM products WHERE id = $1")
+                    .bind(product_id)
+                    .fetch_optional(&mut *conn)
+                    .await?;
+            return Err(match available {
+                Some(available) => ReserveError::NotEnough {
+                    asked: quantity,
+                    available,
+                },
+                None => ReserveError::NoSuchProduct { id: product_id },
+            });
+        };
+
+        let reservation = sqlx::query_as(
+            "INSERT INTO reservations (product_id, quantity, warehouse_id, expires_at) \
+             VALUES ($1, $2, $3, NOW() + make_interval(secs => $4)) \
+             RETURNING id, product_id, quantity, status, warehouse_id",
+        )
+        .bind(product_id)
+        .bind(quantity)
+        .bind(warehouse_id)
+        .bind(ttl.as_secs_f64())
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(reservation)
+    }
+
+    /// Confirms the reservation in the caller's transaction
+    pub async fn confirm_in(
+        conn: &mut PgConnection,
+        reservation_id: i64,
+    ) -> Result<Reservation, ReserveError> {
+        let confirmed = sqlx::query_as(
+            "UPDATE reservations SET status = 'confirmed' \
+             WHERE id = $1 AND status = 'held' AND expires_at > NOW() \
+             RETURNING id, product_id, quantity, status, warehouse_id",
+        )
+        .bind(reservation_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(reservation) = confirmed {
+            return Ok(reservation);
+        }
+
+        match Self::find_in(conn, reservation_id).await? {
+            None => Err(ReserveError::NoSuchReservation { id: reservation_id }),
+            Some(reservation) if reservation.status == ReservationStatus::Confirmed => {
+                Ok(reservation)
+            }
+            // a held one is past its TTL, the sweeper hasn't got to it yet
+            Some(reservation) => Err(ReserveError::NotHeld {
+                id: reservation_id,
+                status: match reservation.status {
+                    ReservationStatus::Held => ReservationStatus::Expired,
+                    status => status,
+                },
+            }),
+        }
+    }
+
+    async fn find_in(
+        conn: &mut PgConnection,
+        reservation_id: i64,
+    ) -> Result<Option<Reservation>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT id, product_id, quantity, status, warehouse_id \
+             FROM reservations WHERE id = $1",
+        )
+        .bind(reservation_id)
+        .fetch_optional(conn)
+        .await
+    }
+
+    /// Gives the stock of a held reservation back, of a confirmed one as well if
+    /// `confirmed_too`
+    async fn release_if(
+        &self,
+        reservation_id: i64,
+        confirmed_too: bool,
+    ) -> Result<(), ReserveError> {
+        let mut tx = self.db.begin().await?;
+        let released: Option<(i64, i64)> = sqlx::query_as(
+            "UPDATE reservations SET status = 'released', released_at = NOW() \
+             WHERE id = $1 AND (status = 'held' OR ($2 AND status = 'confirmed')) \
+             RETURNING product_id, quantity",
+        )
+        .bind(reservation_id)
+        .bind(confirmed_too)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        match released {
+            Some((product_id, quantity)) => {
+                sqlx::query("UPDATE products SET stock = stock + $2 WHERE id = $1")
+                    .bind(product_id)
+                    .bind(quantity)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            None => match Self::find_in(&mut tx, reservation_id).await? {
+                None => return Err(ReserveError::NoSuchReservation { id: reservation_id }),
+                Some(reservation) if reservation.status == ReservationStatus::Confirmed => {
+                    return Err(ReserveError::NotHeld {
+                        id: reservation_id,
+                        status: ReservationStatus::Confirmed,
+                    })
+                }
+                // released or expired already
+                Some(_) => {}
+            },
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Reserves what there is of the item with [`Fulfillment::Partial`], `None` if
+    /// nothing is in stock
+    async fn reserve_item_in(
+        conn: &mut PgConnection,
+        item: &OrderItem,
+        fulfillment: Fulfillment,
+    ) -> Result<Option<Reservation>, ReserveError> {
+        let mut quantity = item.quantity;
+        loop {
+            let reserved =
+                Self::reserve_in(conn, item.product_id, quantity, DEFAULT_RESERVATION_TTL).await;
+            match reserved {
+                Ok(reservation) => return Ok(Some(reservation)),
+                Err(e) => match partial_quantity(fulfillment, &e) {
+                    Some(0) => return Ok(None),
+                    Some(available) => quantity = available,
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ReservationService for PgReservationService {
+    async fn available(&self, product_id: i64) -> Result<i64, ReserveError> {
+        let stock: Option<i64> = sqlx::query_scalar("SELECT stock FROM products WHERE id = $1")
+            .bind(product_id)
+            .fetch_optional(&self.db)
+            .await?;
+        stock.ok_or(ReserveError::NoSuchProduct { id: product_id })
+    }
+
+    async fn reservation(&self, reservation_id: i64) -> Result<Reservation, ReserveError> {
+        let mut conn = self.db.acquire().await?;
+        Self::find_in(&mut conn, reservation_id)
+            .await?
+            .ok_or(ReserveError::NoSuchReservation { id: reservation_id })
+    }
+
+    async fn reserve(&self, product_id: i64, quantity: i64) -> Result<Reservation, ReserveError> {
+        let mut tx = self.db.begin().await?;
+        let reservation = Self::reserve_in(&mut tx, product_id, quantity, self.ttl).await?;
+        tx.commit().await?;
+        Ok(reservation)
+    }
+
+    async fn confirm(&self, reservation_id: i64) -> Result<Reservation, ReserveError> {
+        let mut conn = self.db.acquire().await?;
+        Self::confirm_in(&mut conn, reservation_id).await
+    }
+
+    async fn release(&self, reservation_id: i64) -> Result<(), ReserveError> {
+        self.release_if(reservation_id, true).await
+    }
+
+    async fn release_held(&self, reservation_id: i64) -> Result<(), ReserveError> {
+        self.release_if(reservation_id, false).await
+    }
+
+    async fn expire_held(&self) -> Result<u64, ReserveError> {
+        // data-modifying CTEs run in one statement, so the stock is given back once
+        let expired: i64 = sqlx::query_scalar(
+            "WITH expired AS ( \
+                 UPDATE reservations SET status = 'expired', released_at = NOW() \
+                 WHERE status = 'held' AND expires_at <= NOW() \
+                 RETURNING product_id, quantity \
+             ), restocked AS ( \
+                 UPDATE products SET stock = stock + e.quantity \
+                 FROM (SELECT product_id, SUM(quantity)::BIGINT AS quantity FROM expired GROUP BY product_id) e \
+                 WHERE products.id = e.product_id \
+             ) \
+             SELECT COUNT(*) FROM expired",
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(expired as u64)
+    }
+}
+
+pub struct PgShipmentService {
+    db: PgPool,
+}
+
+impl PgShipmentService {
+    pub fn new(db: PgPool) -> Self {
+        PgShipmentService { db }
+    }
+
+    /// Records the shipment in the caller's transaction, which has to be rolled back
+    /// when some of the reservations are already shipped
+    pub async fn schedule_in(
+        conn: &mut PgConnection,
+        reservations: &[Reservation],
+        address: &Address,
+    ) -> Result<Shipment, ShipmentError> {
+        check_address(address)?;
+        let warehouse_id = check_shipment(reservations)?;
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO shipments (warehouse_id, street, city, postal_code, country) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(warehouse_id)
+        .bind(address.street())
+        .bind(address.city())
+        .bind(address.postal_code())
+        .bind(address.country())
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let ids: Vec<i64> = reservations.iter().map(|r| r.id).collect();
+        let shipped: Vec<i64> = sqlx::query_scalar(
+            "UPDATE reservations SET shipment_id = $1 \
+             WHERE id = ANY($2) AND shipment_id IS NULL RETURNING id",
+        )
+        .bind(id)
+        .bind(&ids)
+        .fetch_all(&mut *conn)
+        .await?;
+        if shipped.len() < ids.len() {
+            return Err(ShipmentError::AlreadyShipped {
+                reservation_ids: ids.into_iter().filter(|id| !shipped.contains(id)).collect(),
+            });
+        }
+
+        Ok(Shipment {
+            id,
+            address: address.clone(),
+            warehouse_id,
+            reservation_ids: ids,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ShipmentService for PgShipmentService {
+    async fn schedule_shipment(
+        &self,
+        reservations: &[Reservation],
+        address: &Address,
+    ) -> Result<Shipment, ShipmentError> {
+        let mut tx = self.db.begin().await?;
+        let shipment = Self::schedule_in(&mut tx, reservations, address).await?;
+        tx.commit().await?;
+        Ok(shipment)
+    }
+
+    async fn shipment_of(&self, reservation_id: i64) -> Result<Option<Shipment>, ShipmentError> {
+        let shipment: Option<(i64, i64, Vec<i64>, String, String, String, String)> =
+            sqlx::query_as(
+                "SELECT s.id, s.warehouse_id, \
+                     ARRAY(SELECT id FROM reservations WHERE shipment_id = s.id ORDER BY id), \
+                     s.street, s.city, s.postal_code, s.country \
+                 FROM reservations r JOIN shipments s ON s.id = r.shipment_id \
+                 WHERE r.id = $1",
+            )
+            .bind(reservation_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(shipment.map(
+            |(id, warehouse_id, reservation_ids, street, city, postal_code, country)| Shipment {
+                id,
+                address: Address {
+                    street,
+                    city,
+                    postal_code,
+                    country,
+                },
+                warehouse_id,
+                reservation_ids,
+            },
+        ))
+    }
+
+    async fn cancel_shipment(&self, shipment_id: i64) -> Result<(), ShipmentError> {
+        let mut tx = self.db.begin().await?;
+        let found: Option<i64> = sqlx::query_scalar(
+            "UPDATE shipments SET canceled_at = COALESCE(canceled_at, NOW()) \
+             WHERE id = $1 RETURNING id",
+        )
+        .bind(shipment_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if found.is_none() {
+            return Err(ShipmentError::NoSuchShipment { id: shipment_id });
+        }
+        sqlx::query("UPDATE reservations SET shipment_id = NULL WHERE shipment_id = $1")
+            .bind(shipment_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Reservation, shipment and purchase are stored in one transaction, a failed step
+/// leaves the stock untouched. The reservation is confirmed right away. Orders are
+/// placed the same way, logged by [`PgOrderLog`] as `Placed` on commit.
+pub struct PgPurchaseService {
+    db: PgPool,
+}
+
+impl PgPurchaseService {
+    pub fn new(db: PgPool) -> Self {
+        PgPurchaseService { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl PurchaseService for PgPurchaseService {
+    async fn purchase(
+        &self,
+        product_id: i64,
+        quantity: i64,
+        address: &Address,
+    ) -> Result<Purchase, PurchaseError> {
+        let mut tx = self.db.begin().await?;
+        let reservation = PgReservationService::reserve_in(
+            &mut tx,
+            product_id,
+            quantity,
+            DEFAULT_RESERVATION_TTL,
+        )
+        .await?;
+        let shipment =
+            PgShipmentService::schedule_in(&mut tx, std::slice::from_ref(&reservation), address)
+                .await?;
+        PgReservationService::confirm_in(&mut tx, reservation.id).await?;
+        let purchase = sqlx::query_as(
+            "INSERT INTO purchases (reservation_id, shipment_id) VALUES ($1, $2) \
+             RETURNING id, reservation_id, shipment_id",
+        )
+        .bind(reservation.id)
+        .bind(shipment.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(purchase)
+    }
+
+    async fn find_purchase(&self, purchase_id: i64) -> Result<Option<Purchase>, PurchaseError> {
+        let purchase =
+            sqlx::query_as("SELECT id, reservation_id, shipment_id FROM purchases WHERE id = $1")
+                .bind(purchase_id)
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(purchase)
+    }
+
+    async fn place_order(
+        &self,
+        items: &[OrderItem],
+        address: &Address,
+        fulfillment: Fulfillment,
+    ) -> Result<Order, PurchaseError> {
+        check_items(items)?;
+        let mut tx = self.db.begin().await?;
+        let id = PgOrderLog::start_in(&mut tx, fulfillment, address).await?;
+
+        let mut lines = Vec::new();
+        let mut reservations = Vec::new();
+        for item in items {
+            let reservation =
+                PgReservationService::reserve_item_in(&mut tx, item, fulfillment).await?;
+            let line = OrderLine::new(item, reservation.as_ref());
+            PgOrderLog::add_line_in(&mut tx, id, &line).await?;
+            lines.push(line);
+            reservations.extend(reservation);
+        }
+
+        let mut shipments = Vec::new();
+        for group in by_warehouse(&reservations) {
+            shipments.push(PgShipmentService::schedule_in(&mut tx, &group, address).await?);
+        }
+        for reservation in &reservations {
+            PgReservationService::confirm_in(&mut tx, reservation.id).await?;
+        }
+        PgOrderLog::set_status_in(&mut tx, id, OrderStatus::Placed).await?;
+        tx.commit().await?;
+        Ok(Order {
+            id,
+            fulfillment,
+            status: OrderStatus::Placed,
+            lines,
+            shipments,
+        })
+    }
+
+    async fn find_order(&self, order_id: i64) -> Result<Option<Order>, PurchaseError> {
+        order::find_order(
+            &PgShipmentService::new(self.db.clone()),
+            &PgOrderLog::new(self.db.clone()),
+            order_id,
+        )
+        .await
+    }
+}
+
+/// `orders` and `order_lines` tables
+pub struct PgOrderLog {
+    db: PgPool,
+}
+
+impl PgOrderLog {
+    pub fn new(db: PgPool) -> Self {
+        PgOrderLog { db }
+    }
+
+    /// Records a new `Placing` order in the caller's transaction
+    pub async fn start_in(
+        conn: &mut PgConnection,
+        fulfillment: Fulfillment,
+        address: &Address,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "INSERT INTO orders (fulfillment, street, city, postal_code, country) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(fulfillment)
+        .bind(address.street())
+        .bind(address.city())
+        .bind(address.postal_code())
+        .bind(address.country())
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Records the line in the caller's transaction
+    pub async fn add_line_in(
+        conn: &mut PgConnection,
+        order_id: i64,
+        line: &OrderLine,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO order_lines \
+             (order_id, product_id, ordered, reservation_id, backordered) \
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(order_id)
+        .bind(line.product_id)
+        .bind(line.ordered)
+        .bind(line.reservation_id)
+        .bind(line.backordered)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_status_in(
+        conn: &mut PgConnection,
+        order_id: i64,
+        status: OrderStatus,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE orders SET status = $2 WHERE id = $1")
+            .bind(order_id)
+            .bind(status)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl OrderLog for PgOrderLog {
+    async fn start(&self, fulfillment: Fulfillment, address: &Address) -> Result<i64, sqlx::Error> {
+        let mut conn = self.db.acquire().await?;
+        Self::start_in(&mut conn, fulfillment, address).await
+    }
+
+    async fn add_line(&self, order_id: i64, line: &OrderLine) -> Result<(), sqlx::Error> {
+        let mut conn = self.db.acquire().await?;
+        Self::add_line_in(&mut conn, order_id, line).await
+    }
+
+    async fn set_status(&self, order_id: i64, status: OrderStatus) -> Result<(), sqlx::Error> {
+        let mut conn = self.db.acquire().await?;
+        Self::set_status_in(&mut conn, order_id, status).await
+    }
+
+    async fn order(&self, order_id: i64) -> Result<Option<OrderRecord>, sqlx::Error> {
+        let order: Option<(Fulfillment, OrderStatus)> =
+            sqlx::query_as("SELECT fulfillment, status FROM orders WHERE id = $1")
+                .bind(order_id)
+                .fetch_optional(&self.db)
+                .await?;
+        let Some((fulfillment, status)) = order else {
+            return Ok(None);
+        };
+        let lines = sqlx::query_as(
+            "SELECT product_id, ordered, reservation_id, ordered - backordered AS reserved, \
+             backordered FROM order_lines WHERE order_id = $1 ORDER BY id",
+        )
+        .bind(order_id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(Some(OrderRecord {
+            id: order_id,
+            fulfillment,
+            status,
+            lines,
+        }))
+    }
+
+    async fn unfinished(&self) -> Result<Vec<OrderRecord>, sqlx::Error> {
+        let ids: Vec<i64> =
+            sqlx::query_scalar("SELECT id FROM orders WHERE status = 'placing' ORDER BY id")
+                .fetch_all(&self.db)
+                .await?;
+        let mut orders = Vec::new();
+        for id in ids {
+            orders.extend(self.order(id).await?);
+        }
+        Ok(orders)
+    }
+}
+
+/// `purchase_saga_steps` table
+pub struct PgSagaLog {
+    db: PgPool,
+}
+
+impl PgSagaLog {
+    pub fn new(db: PgPool) -> Self {
+        PgSagaLog { db }
+    }
+}
+
+#[derive(Debug, Clone, Copy, sqlx::Type)]
+#[sqlx(type_name = "purchase_saga_step", rename_all = "lowercase")]
+enum StepKind {
+    Started,
+    Reserved,
+    Shipped,
+    Completed,
+    Compensating,
+    Compensated,
+    Aborted,
+}
+
+#[derive(FromRow)]
+struct StepRow {
+    saga_id: i64,
+    step: StepKind,
+    product_id: Option<i64>,
+    quantity: Option<i64>,
+    street: Option<String>,
+    city: Option<String>,
+    postal_code: Option<String>,
+    country: Option<String>,
+    reservation_id: Option<i64>,
+    shipment_id: Option<i64>,
+    error: Option<String>,
+}
+
+impl StepRow {
+    fn new(saga_id: i64, step: &SagaStep) -> StepRow {
+        let mut row = StepRow {
+            saga_id,
+            step: StepKind::Started,
+            product_id: None,
+            quantity: None,
+            street: None,
+            city: None,
+            postal_code: None,
+            country: None,
+            reservation_id: None,
+            shipment_id: None,
+            error: None,
+        };
+        match step {
+            SagaStep::Started {
+                product_id,
+                quantity,
+                address,
+            } => {
+                row.product_id = Some(*product_id);
+                row.quantity = Some(*quantity);
+                row.street = Some(address.street.clone());
+                row.city = Some(address.city.clone());
+                row.postal_code = Some(address.postal_code.clone());
+                row.country = Some(address.country.clone());
+            }
+            SagaStep::Reserved { reservation_id } => {
+                row.step = StepKind::Reserved;
+                row.reservation_id = Some(*reservation_id);
+            }
+            SagaStep::Shipped { shipment_id } => {
+                row.step = StepKind::Shipped;
+                row.shipment_id = Some(*shipment_id);
+            }
+            SagaStep::Completed => row.step = StepKind::Completed,
+            SagaStep::Compensating { error } => {
+                row.step = StepKind::Compensating;
+                row.error = Some(error.clone());
+            }
+            SagaStep::Compensated => row.step = StepKind::Compensated,
+            SagaStep::Aborted { error } => {
+                row.step = StepKind::Aborted;
+                row.error = Some(error.clone());
+            }
+        }
+        row
+    }
+
+    fn into_step(self) -> Result<SagaStep, sqlx::Error> {
+        let missing = |column: &str| {
+            sqlx::Error::Decode(
+                format!("saga {} step {:?} has no {column}", self.saga_id, self.step).into(),
+            )
+        };
+        Ok(match self.step {
+            StepKind::Started => SagaStep::Started {
+                product_id: self.product_id.ok_or_else(|| missing("product_id"))?,
+                quantity: self.quantity.ok_or_else(|| missing("quantity"))?,
+                address: Address {
+                    street: self.street.clone().ok_or_else(|| missing("street"))?,
+                    city: self.city.clone().ok_or_else(|| missing("city"))?,
+                    postal_code: self
+                        .postal_code
+                        .clone()
+                        .ok_or_else(|| missing("postal_code"))?,
+                    country: self.country.clone().ok_or_else(|| missing("country"))?,
+                },
+            },
+            StepKind::Reserved => SagaStep::Reserved {
+                reservation_id: self
+                    .reservation_id
+                    .ok_or_else(|| missing("reservation_id"))?,
+            },
+            StepKind::Shipped => SagaStep::Shipped {
+                shipment_id: self.shipment_id.ok_or_else(|| missing("shipment_id"))?,
+            },
+            StepKind::Completed => SagaStep::Completed,
+            StepKind::Compensating => SagaStep::Compensating {
+                error: self.error.clone().unwrap_or_default(),
+            },
+            StepKind::Compensated => SagaStep::Compensated,
+            StepKind::Aborted => SagaStep::Aborted {
+                error: self.error.clone().unwrap_or_default(),
+            },
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SagaLog for PgSagaLog {
+    async fn start(
+        &self,
+        product_id: i64,
+        quantity: i64,
+        address: &Address,
+    ) -> Result<i64, sqlx::Error> {
+        let saga_id: i64 = sqlx::query_scalar("SELECT nextval('purchase_sagas_seq')")
+            .fetch_one(&self.db)
+            .await?;
+        let step = SagaStep::Started {
+            product_id,
+            quantity,
+            address: address.clone(),
+        };
+        self.append(saga_id, &step).await?;
+        Ok(saga_id)
+    }
+
+    async fn append(&self, saga_id: i64, step: &SagaStep) -> Result<(), sqlx::Error> {
+        let row = StepRow::new(saga_id, step);
+        sqlx::query(
+            "INSERT INTO purchase_saga_steps \
+             (saga_id, step, product_id, quantity, street, city, postal_code, country, \
+              reservation_id, shipment_id, error) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        )
+        .bind(row.saga_id)
+        .bind(row.step)
+        .bind(row.product_id)
+        .bind(row.quantity)
+        .bind(row.street)
+        .bind(row.city)
+        .bind(row.postal_code)
+        .bind(row.country)
+        .bind(row.reservation_id)
+        .bind(row.shipment_id)
+        .bind(row.error)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn saga(&self, saga_id: i64) -> Result<Option<Saga>, sqlx::Error> {
+        let rows: Vec<StepRow> = sqlx::query_as(
+            "SELECT saga_id, step, product_id, quantity, street, city, postal_code, country, \
+                 reservation_id, shipment_id, error \
+             FROM purchase_saga_steps WHERE saga_id = $1 ORDER BY id",
+        )
+        .bind(saga_id)
+        .fetch_all(&self.db)
+        .await?;
+        let steps = rows
+            .into_iter()
+            .map(StepRow::into_step)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Saga::from_steps(saga_id, steps))
+    }
+
+    async fn unfinished(&self) -> Result<Vec<Saga>, sqlx::Error> {
+        let rows: Vec<StepRow> = sqlx::query_as(
+            "SELECT saga_id, step, product_id, quantity, street, city, postal_code, country, \
+                 reservation_id, shipment_id, error \
+             FROM purchase_saga_steps \
+             WHERE saga_id IN ( \
+                 SELECT saga_id FROM purchase_saga_steps GROUP BY saga_id \
+                 HAVING NOT bool_or(step IN ('completed', 'compensated', 'aborted')) \
+             ) \
+             ORDER BY saga_id, id",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut sagas = Vec::new();
+        let mut rows = rows.into_iter().peekable();
+        while let Some(first) = rows.next() {
+            let saga_id = first.saga_id;
+            let mut steps = vec![first.into_step()?];
+            while let Some(row) = rows.next_if(|row| row.saga_id == saga_id) {
+                steps.push(row.into_step()?);
+            }
+            sagas.extend(Saga::from_steps(saga_id, steps));
+        }
+        Ok(sagas)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{shop::SagaPurchaseService, test_db::start_postgres};
+    use std::sync::Arc;
+
+    fn address() -> Address {
+        Address::new("Stefan cel Mare 1", "Chisinau", "MD-2001", "MD").unwrap()
+    }
+
+    // stored before the postal code rules, so the shipment rejects it
+    fn invalid_address() -> Address {
+        Address::unchecked("Stefan cel Mare 1", "Chisinau", "2001-MD", "MD")
+    }
+
+    async fn stock(db: &PgPool, product_id: i64) -> i64 {
+        sqlx::query_scalar("SELECT stock FROM products WHERE id = $1")
+            .bind(product_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_purchase_in_one_transaction() {
+        let (_container, db) = start_postgres().await;
+        let product_id: i64 = sqlx::query_scalar(
+            "INSERT INTO products (name, stock) VALUES ('Pen', 50) RETURNING id",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let purchases = PgPurchaseService::new(db.clone());
+
+        assert!(matches!(
+            purchases.purchase(product_id + 1, 1, &address()).await,
+            Err(PurchaseError::ReservationFailed(
+                ReserveError::NoSuchProduct { .. }
+            ))
+        ));
+        assert!(matches!(
+            purchases.purchase(product_id, 51, &address()).await,
+            Err(PurchaseError::ReservationFailed(ReserveError::NotEnough {
+                asked: 51,
+                available: 50
+            }))
+        ));
+
+        // the reservation is rolled back with the failed shipment
+        assert!(matches!(
+            purchases.purchase(product_id, 10, &invalid_address()).await,
+            Err(PurchaseError::ShippingFailed(
+                ShipmentError::InvalidAddress { .. }
+            ))
+        ));
+        assert_eq!(stock(&db, product_id).await, 50);
+        let reservations: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reservations")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(reservations, 0);
+
+        let purchase = purchases
+            .purchase(product_id, 10, &address())
+            .await
+            .unwrap();
+        assert_eq!(stock(&db, product_id).await, 40);
+        let shipped: Address = sqlx::query_as(
+            "SELECT street, city, postal_code, country FROM shipments s \
+             JOIN reservations r ON r.shipment_id = s.id \
+             WHERE s.id = $1 AND r.id = $2",
+        )
+        .bind(purchase.shipment_id)
+        .bind(purchase.reservation_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(shipped, address());
+        assert_eq!(
+            purchases.find_purchase(purchase.id).await.unwrap(),
+            Some(purchase)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_saga_over_postgres() {
+        let (_container, db) = start_postgres().await;
+        let product_id: i64 = sqlx::query_scalar(
+            "INSERT INTO products (name, stock) VALUES ('Pen', 50) RETURNING id",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let reservations = Arc::new(PgReservationService::new(db.clone()));
+        let log = Arc::new(PgSagaLog::new(db.clone()));
+        let order_log = Arc::new(PgOrderLog::new(db.clone()));
+        let purchases = SagaPurchaseService::new(
+            reservations.clone(),
+            Arc::new(PgShipmentService::new(db.clone())),
+            log.clone(),
+            order_log.clone(),
+        );
+
+        // the shipment fails after the reservation is committed
+        assert!(purchases
+            .purchase(product_id, 10, &invalid_address())
+            .await
+            .is_err());
+        assert_eq!(stock(&db, product_id).await, 50);
+
+        // crashed before the shipment
+        let saga_id = log.start(product_id, 5, &address()).await.unwrap();
+        let reservation = reservations.reserve(product_id, 5).await.unwrap();
+        let step = SagaStep::Reserved {
+            reservation_id: reservation.id,
+        };
+        log.append(saga_id, &step).await.unwrap();
+        assert_eq!(
+            log.unfinished().await.unwrap()[0].reservation_id,
+            Some(reservation.id)
+        );
+
+        let recovered = purchases.recover().await.unwrap();
+        assert_eq!(recovered.len(), 1);
+        let purchase = recovered[0].1.as_ref().unwrap();
+        assert_eq!(purchase.id, saga_id);
+        assert_eq!(
+            purchases.find_purchase(saga_id).await.unwrap().as_ref(),
+            Some(purchase)
+        );
+        assert_eq!(stock(&db, product_id).await, 45);
+        assert!(log.unfinished().await.unwrap().is_empty());
+
+        // an order crashed after its first line
+        let order_id = order_log
+            .start(Fulfillment::AllOrNothing, &address())
+            .await
+            .unwrap();
+        let item = OrderItem {
+            product_id,
+            quantity: 5,
+        };
+        let line = OrderLine::new(
+            &item,
+            Some(&reservations.reserve(product_id, 5).await.unwrap()),
+        );
+        order_log.add_line(order_id, &line).await.unwrap();
+        assert_eq!(stock(&db, product_id).await, 40);
+        assert_eq!(order_log.unfinished().await.unwrap()[0].lines, [line]);
+
+        let recovered = purchases.recover_orders().await.unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert!(recovered[0].1.is_ok());
+        assert_eq!(stock(&db, product_id).await, 45);
+        let order = purchases.find_order(order_id).await.unwrap().unwrap();
+        assert_eq!(order.status, OrderStatus::Failed);
+        assert!(order_log.unfinished().await.unwrap().is_empty());
+
+        reservations.release(reservation.id).await.unwrap();
+        reservations.release(reservation.id).await.unwrap();
+        assert_eq!(stock(&db, product_id).await, 50);
+        assert!(matches!(
+            reservations.release(-1).await,
+            Err(ReserveError::NoSuchReservation { id: -1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expire_held() {
+        let (_container, db) = start_postgres().await;
+        let product_id: i64 = sqlx::query_scalar(
+            "INSERT INTO products (name, stock) VALUES ('Pen', 50) RETURNING id",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let reservations = PgReservationService::new(db.clone());
+        let expiring = PgReservationService::new(db.clone()).with_ttl(Duration::ZERO);
+
+        let held = reservations.reserve(product_id, 5).await.unwrap();
+        let confirmed = expiring.reserve(product_id, 10).await.unwrap();
+        let expired = expiring.reserve(product_id, 20).await.unwrap();
+        sqlx::query("UPDATE reservations SET status = 'confirmed' WHERE id = $1")
+            .bind(confirmed.id)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(stock(&db, product_id).await, 15);
+        assert!(matches!(
+            expiring.confirm(expired.id).await,
+            Err(ReserveError::NotHeld {
+                status: ReservationStatus::Expired,
+                ..
+            })
+        ));
+
+        assert_eq!(reservations.expire_held().await.unwrap(), 1);
+        assert_eq!(reservations.expire_held().await.unwrap(), 0);
+        assert_eq!(stock(&db, product_id).await, 35);
+        assert_eq!(
+            reservations.confirm(held.id).await.unwrap().status,
+            ReservationStatus::Confirmed
+        );
+        assert!(matches!(
+            reservations.release_held(held.id).await,
+            Err(ReserveError::NotHeld {
+                status: ReservationStatus::Confirmed,
+                ..
+            })
+        ));
+        reservations.release_held(expired.id).await.unwrap();
+        // expired stock is given back once
+        reservations.release(expired.id).await.unwrap();
+        assert_eq!(stock(&db, product_id).await, 35);
+    }
+
+    #[tokio::test]
+    async fn test_place_order() {
+        let (_container, db) = start_postgres().await;
+        let warehouse_id: i64 =
+            sqlx::query_scalar("INSERT INTO warehouses (name) VALUES ('north') RETURNING id")
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        let pen: i64 = sqlx::query_scalar(
+            "INSERT INTO products (name, stock) VALUES ('Pen', 50) RETURNING id",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let ink: i64 = sqlx::query_scalar(
+            "INSERT INTO products (name, stock, warehouse_id) VALUES ('Ink', 5, $1) RETURNING id",
+        )
+        .bind(warehouse_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let purchases = PgPurchaseService::new(db.clone());
+        let items = [
+            OrderItem {
+                product_id: pen,
+                quantity: 10,
+            },
+            OrderItem {
+                product_id: ink,
+                quantity: 8,
+            },
+        ];
+
+        assert!(matches!(
+            purchases
+                .place_order(&items, &address(), Fulfillment::AllOrNothing)
+                .await,
+            Err(PurchaseError::ReservationFailed(ReserveError::NotEnough {
+                asked: 8,
+                available: 5
+            }))
+        ));
+        assert_eq!(stock(&db, pen).await, 50);
+
+        let order = purchases
+            .place_order(&items, &address(), Fulfillment::Partial)
+            .await
+            .unwrap();
+        assert_eq!(order.lines[1].backordered, 3);
+        assert_eq!(stock(&db, pen).await, 40);
+        assert_eq!(stock(&db, ink).await, 0);
+        let warehouses: Vec<i64> = order.shipments.iter().map(|s| s.warehouse_id).collect();
+        assert_eq!(warehouses, [1000, warehouse_id]);
+        assert_eq!(
+            purchases.find_order(order.id).await.unwrap().as_ref(),
+            Some(&order)
+        );
+
+        let shipped: Vec<(i64, Option<i64>, ReservationStatus)> = sqlx::query_as(
+            "SELECT r.product_id, r.shipment_id, r.status FROM order_lines l \
+             JOIN reservations r ON r.id = l.reservation_id \
+             WHERE l.order_id = $1 ORDER BY l.id",
+        )
+        .bind(order.id)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            shipped,
+            [
+                (
+                    pen,
+                    Some(order.shipments[0].id),
+                    ReservationStatus::Confirmed
+                ),
+                (
+                    ink,
+                    Some(order.shipments[1].id),
+                    ReservationStatus::Confirmed
+                )
+            ]
+        );
+
+        // a reservation is shipped once
+        let shipments = PgShipmentService::new(db.clone());
+        let reservation = PgReservationService::new(db.clone())
+            .reservation(order.lines[0].reservation_id.unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            shipments
+                .schedule_shipment(std::slice::from_ref(&reservation), &address())
+                .await,
+            Err(ShipmentError::AlreadyShipped { .. })
+        ));
+        // unless its shipment is canceled
+        shipments
+            .cancel_shipment(order.shipments[0].id)
+            .await
+            .unwrap();
+        shipments
+            .cancel_shipment(order.shipments[0].id)
+            .await
+            .unwrap();
+        shipments
+            .schedule_shipment(&[reservation], &address())
+            .await
+            .unwrap();
+        assert!(matches!(
+            shipments.cancel_shipment(-1).await,
+            Err(ShipmentError::NoSuchShipment { id: -1 })
+        ));
+    }
+}
@@ -0,0 +1,552 @@
+//! Purchase as a saga over services that don't share a transaction: every step is
+//! appended to a log, a failed shipment releases the reservation, a failed confirmation
+//! cancels the shipment as well, and purchases
+//! interrupted by a crash are resumed or compensated by [`SagaPurchaseService::recover`].
+//!
+//! A shipment scheduled right before a crash is found through its reservation and
+//! adopted. A reservation made right before a crash is not known to the log and stays
+//! reserved until it expires.
+
+use std::sync::Arc;
+
+use super::{
+    order, Address, Fulfillment, Order, OrderItem, OrderLog, Purchase, PurchaseError,
+    PurchaseService, ReservationService, ShipmentService,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SagaStep {
+    Started {
+        product_id: i64,
+        quantity: i64,
+        address: Address,
+    },
+    Reserved {
+        reservation_id: i64,
+    },
+    Shipped {
+        shipment_id: i64,
+    },
+    /// The reservation is confirmed
+    Completed,
+    /// The shipment or the confirmation failed, the shipment if any is being canceled and
+    /// the reservation released
+    Compensating {
+        error: String,
+    },
+    Compensated,
+    /// Failed or interrupted before anything had to be compensated
+    Aborted {
+        error: String,
+    },
+}
+
+impl SagaStep {
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            SagaStep::Completed | SagaStep::Compensated | SagaStep::Aborted { .. }
+        )
+    }
+}
+
+/// A saga folded from its steps
+#[derive(Debug, Clone, PartialEq)]
+pub struct Saga {
+    pub id: i64,
+    pub product_id: i64,
+    pub quantity: i64,
+    pub address: Address,
+    pub reservation_id: Option<i64>,
+    pub shipment_id: Option<i64>,
+    pub last_step: SagaStep,
+}
+
+impl Saga {
+    /// `None` if the first step is not `Started`
+    pub fn from_steps(id: i64, steps: impl IntoIterator<Item = SagaStep>) -> Option<Saga> {
+        let mut steps = steps.into_iter();
+        let first = steps.next()?;
+        let SagaStep::Started {
+            product_id,
+            quantity,
+            ref address,
+        } = first
+        else {
+            return None;
+        };
+        let mut saga = Saga {
+            id,
+            product_id,
+            quantity,
+            address: address.clone(),
+            reservation_id: None,
+            shipment_id: None,
+            last_step: first,
+        };
+        for step in steps {
+            saga.apply(step);
+        }
+        Some(saga)
+    }
+
+    fn apply(&mut self, step: SagaStep) {
+        match step {
+            SagaStep::Reserved { reservation_id } => self.reservation_id = Some(reservation_id),
+            SagaStep::Shipped { shipment_id } => self.shipment_id = Some(shipment_id),
+            _ => {}
+        }
+        self.last_step = step;
+    }
+}
+
+/// Append-only log of the saga steps
+#[async_trait::async_trait]
+pub trait SagaLog: Send + Sync {
+    /// Appends the `Started` step of a new saga and returns its ID
+    async fn start(
+        &self,
+        product_id: i64,
+        quantity: i64,
+        address: &Address,
+    ) -> Result<i64, sqlx::Error>;
+
+    async fn append(&self, saga_id: i64, step: &SagaStep) -> Result<(), sqlx::Error>;
+
+    /// `None` if there is no saga with the ID
+    async fn saga(&self, saga_id: i64) -> Result<Option<Saga>, sqlx::Error>;
+
+    /// Sagas without a final step
+    async fn unfinished(&self) -> Result<Vec<Saga>, sqlx::Error>;
+}
+
+/// Reserves, schedules the shipment, confirms the reservation and releases it when the
+/// shipment or the confirmation fails. The ID of a purchase is the ID of its saga.
+///
+/// Orders are logged in an [`OrderLog`] instead.
+pub struct SagaPurchaseService {
+    reservation_service: Arc<dyn ReservationService>,
+    shipment_service: Arc<dyn ShipmentService>,
+    log: Arc<dyn SagaLog>,
+    order_log: Arc<dyn OrderLog>,
+}
+
+impl SagaPurchaseService {
+    pub fn new(
+        reservation_service: Arc<dyn ReservationService>,
+        shipment_service: Arc<dyn ShipmentService>,
+        log: Arc<dyn SagaLog>,
+        order_log: Arc<dyn OrderLog>,
+    ) -> Self {
+        SagaPurchaseService {
+            reservation_service,
+            shipment_service,
+            log,
+            order_log,
+        }
+    }
+
+    /// Compensates the orders interrupted by a crash
+    pub async fn recover_orders(
+        &self,
+    ) -> Result<Vec<(i64, Result<(), PurchaseError>)>, sqlx::Error> {
+        order::recover(
+            self.reservation_service.as_ref(),
+            self.shipment_service.as_ref(),
+            self.order_log.as_ref(),
+        )
+        .await
+    }
+
+    /// Finishes the sagas interrupted by a crash: the ones with a reservation are resumed
+    /// (and compensated if the shipment fails now), the ones stopped while compensating
+    /// are compensated again, and the ones without a reservation are aborted
+    pub async fn recover(
+        &self,
+    ) -> Result<Vec<(i64, Result<Purchase, PurchaseError>)>, sqlx::Error> {
+        let mut recovered = Vec::new();
+        for mut saga in self.log.unfinished().await? {
+            let outcome = match saga.last_step {
+                // the reservation may have been made before the crash, but the log doesn't
+                // know it, so it's not retried
+                SagaStep::Started { .. } => {
+                    let error = "interrupted before the reservation".to_string();
+                    self.log
+                        .append(
+                            saga.id,
+                            &SagaStep::Aborted {
+                                error: error.clone(),
+                            },
+                        )
+                        .await?;
+                    Err(PurchaseError::Interrupted(error))
+                }
+                _ => self.run(&mut saga).await,
+            };
+            tracing::info!("Recovered purchase saga {}: {:?}", saga.id, outcome);
+            recovered.push((saga.id, outcome));
+        }
+        Ok(recovered)
+    }
+
+    /// Continues `saga` after its last step until a final one
+    async fn run(&self, saga: &mut Saga) -> Result<Purchase, PurchaseError> {
+        loop {
+            let step = match (&saga.last_step, saga.reservation_id, saga.shipment_id) {
+                (SagaStep::Started { .. }, _, _) => {
+                    match self
+                        .reservation_service
+                        .reserve(saga.product_id, saga.quantity)
+                        .await
+                    {
+                        Ok(reservation) => SagaStep::Reserved {
+                            reservation_id: reservation.id,
+                        },
+                        Err(e) => {
+                            let step = SagaStep::Aborted {
+                                error: e.to_string(),
+                            };
+                            self.log.append(saga.id, &step).await?;
+                            return Err(e.into());
+                        }
+                    }
+                }
+                (SagaStep::Reserved { .. }, Some(reservation_id), _) => {
+                    // shipped right before a crash, the `Shipped` step wasn't logged
+                    let shipped = match self.shipment_service.shipment_of(reservation_id).await {
+                        Ok(shipped) => shipped,
+                        Err(e) => return self.fail(saga, e.into()).await,
+                    };
+                    if let Some(shipment) = shipped {
+                        SagaStep::Shipped {
+                            shipment_id: shipment.id,
+                        }
+                    } else {
+                        let reservation =
+                            match self.reservation_service.reservation(reservation_id).await {
+                                Ok(reservation) => reservation,
+                                Err(e) => return self.fail(saga, e.into()).await,
+                            };
+                        match self
+                            .shipment_service
+                            .schedule_shipment(std::slice::from_ref(&reservation), &saga.address)
+                            .await
+                        {
+                            Ok(shipment) => SagaStep::Shipped {
+                                shipment_id: shipment.id,
+                            },
+                            Err(e) => return self.fail(saga, e.into()).await,
+                        }
+                    }
+                }
+                (SagaStep::Shipped { .. }, Some(reservation_id), Some(shipment_id)) => {
+                    if let Err(e) = self.reservation_service.confirm(reservation_id).await {
+                        return self.fail(saga, e.into()).await;
+                    }
+                    self.log.append(saga.id, &SagaStep::Completed).await?;
+                    saga.apply(SagaStep::Completed);
+                    return Ok(Purchase {
+                        id: saga.id,
+                        reservation_id,
+                        shipment_id,
+                    });
+                }
+                (SagaStep::Compensating { error }, _, _) => {
+                    let error = error.clone();
+                    self.compensate(saga).await?;
+                    return Err(PurchaseError::Interrupted(error));
+                }
+                (step, _, _) => {
+                    return Err(PurchaseError::Interrupted(format!(
+                        "saga {} cannot continue after {step:?}",
+                        saga.id
+                    )));
+                }
+            };
+            self.log.append(saga.id, &step).await?;
+            saga.apply(step);
+        }
+    }
+
+    /// Compensates `saga` after `error` and returns it
+    async fn fail(&self, saga: &mut Saga, error: PurchaseError) -> Result<Purchase, PurchaseError> {
+        let step = SagaStep::Compensating {
+            error: error.to_string(),
+        };
+        self.log.append(saga.id, &step).await?;
+        saga.apply(step);
+        // a failed release is retried by the recovery
+        if let Err(release_error) = self.compensate(saga).await {
+            tracing::warn!(
+                "Cannot compensate purchase saga {}: {release_error}",
+                saga.id
+            );
+        }
+        Err(error)
+    }
+
+    async fn compensate(&self, saga: &mut Saga) -> Result<(), PurchaseError> {
+        if let Some(shipment_id) = saga.shipment_id {
+            self.shipment_service.cancel_shipment(shipment_id).await?;
+        }
+        if let Some(reservation_id) = saga.reservation_id {
+            self.reservation_service.release(reservation_id).await?;
+        }
+        self.log.append(saga.id, &SagaStep::Compensated).await?;
+        saga.apply(SagaStep::Compensated);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl PurchaseService for SagaPurchaseService {
+    async fn purchase(
+        &self,
+        product_id: i64,
+        quantity: i64,
+        address: &Address,
+    ) -> Result<Purchase, PurchaseError> {
+        let id = self.log.start(product_id, quantity, address).await?;
+        let mut saga = Saga {
+            id,
+            product_id,
+            quantity,
+            address: address.clone(),
+            reservation_id: None,
+            shipment_id: None,
+            last_step: SagaStep::Started {
+                product_id,
+                quantity,
+                address: address.clone(),
+            },
+        };
+        self.run(&mut saga).await
+    }
+
+    async fn find_purchase(&self, purchase_id: i64) -> Result<Option<Purchase>, PurchaseError> {
+        let Some(saga) = self.log.saga(purchase_id).await? else {
+            return Ok(None);
+        };
+        Ok(
+            match (saga.last_step, saga.reservation_id, saga.shipment_id) {
+                (SagaStep::Completed, Some(reservation_id), Some(shipment_id)) => Some(Purchase {
+                    id: saga.id,
+                    reservation_id,
+                    shipment_id,
+                }),
+                _ => None,
+            },
+        )
+    }
+
+    async fn place_order(
+        &self,
+        items: &[OrderItem],
+        address: &Address,
+        fulfillment: Fulfillment,
+    ) -> Result<Order, PurchaseError> {
+        order::check_items(items)?;
+        order::place_order(
+            self.reservation_service.as_ref(),
+            self.shipment_service.as_ref(),
+            self.order_log.as_ref(),
+            items,
+            address,
+            fulfillment,
+        )
+        .await
+    }
+
+    async fn find_order(&self, order_id: i64) -> Result<Option<Order>, PurchaseError> {
+        order::find_order(
+            self.shipment_service.as_ref(),
+            self.order_log.as_ref(),
+            order_id,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shop::{
+        InMemoryOrderLog, InMemoryReservationService, InMemorySagaLog, InMemoryShipmentService,
+        ReservationStatus, ReserveError, ShipmentError,
+    };
+    use std::collections::HashMap;
+
+    fn address() -> Address {
+        Address::new("Stefan cel Mare 1", "Chisinau", "MD-2001", "MD").unwrap()
+    }
+
+    // stored before the postal code rules, so the shipment rejects it
+    fn invalid_address() -> Address {
+        Address::unchecked("Stefan cel Mare 1", "Chisinau", "2001-MD", "MD")
+    }
+
+    struct Shop {
+        reservations: Arc<InMemoryReservationService>,
+        shipments: Arc<InMemoryShipmentService>,
+        log: Arc<InMemorySagaLog>,
+        purchases: SagaPurchaseService,
+    }
+
+    fn shop() -> Shop {
+        let reservations = Arc::new(InMemoryReservationService::new(HashMap::from([(111, 50)])));
+        let shipments = Arc::new(InMemoryShipmentService::new());
+        let log = Arc::new(InMemorySagaLog::new());
+        let purchases = SagaPurchaseService::new(
+            reservations.clone(),
+            shipments.clone(),
+            log.clone(),
+            Arc::new(InMemoryOrderLog::new()),
+        );
+        Shop {
+            reservations,
+            shipments,
+            log,
+            purchases,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_shipment_releases_reservation() {
+        let shop = shop();
+
+        assert!(matches!(
+            shop.purchases.purchase(112, 1, &address()).await,
+            Err(PurchaseError::ReservationFailed(
+                ReserveError::NoSuchProduct { id: 112 }
+            ))
+        ));
+        assert!(matches!(
+            shop.purchases.purchase(111, 10, &invalid_address()).await,
+            Err(PurchaseError::ShippingFailed(
+                ShipmentError::InvalidAddress { .. }
+            ))
+        ));
+        assert_eq!(shop.reservations.stock(111), Some(50));
+
+        let purchase = shop.purchases.purchase(111, 10, &address()).await.unwrap();
+        assert_eq!(shop.reservations.stock(111), Some(40));
+        assert_eq!(purchase.id, 1002);
+        assert_eq!(
+            shop.purchases.find_purchase(purchase.id).await.unwrap(),
+            Some(purchase)
+        );
+        // failed purchases are not found
+        assert_eq!(shop.purchases.find_purchase(1001).await.unwrap(), None);
+        assert!(shop.log.unfinished().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let shop = shop();
+        let reserve = |quantity| shop.reservations.reserve(111, quantity);
+
+        // crashed before the reservation
+        shop.log.start(111, 1, &address()).await.unwrap();
+        // crashed before the shipment
+        let shipped = shop.log.start(111, 2, &address()).await.unwrap();
+        let reservation = reserve(2).await.unwrap();
+        let step = SagaStep::Reserved {
+            reservation_id: reservation.id,
+        };
+        shop.log.append(shipped, &step).await.unwrap();
+        // crashed before the release
+        let released = shop.log.start(111, 3, &invalid_address()).await.unwrap();
+        let reservation = reserve(3).await.unwrap();
+        let step = SagaStep::Reserved {
+            reservation_id: reservation.id,
+        };
+        shop.log.append(released, &step).await.unwrap();
+        let step = SagaStep::Compensating {
+            error: "Invalid postal code: not a postal code".into(),
+        };
+        shop.log.append(released, &step).await.unwrap();
+        assert_eq!(shop.reservations.stock(111), Some(45));
+
+        let recovered = shop.purchases.recover().await.unwrap();
+        let outcomes: Vec<_> = recovered
+            .iter()
+            .map(|(id, outcome)| (*id, outcome.is_ok()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [(1000, false), (shipped, true), (released, false)]
+        );
+        assert_eq!(shop.reservations.stock(111), Some(48));
+        assert!(shop.log.unfinished().await.unwrap().is_empty());
+        // compensating twice gives the stock back once
+        shop.reservations.release(reservation.id).await.unwrap();
+        assert_eq!(shop.reservations.stock(111), Some(48));
+    }
+
+    #[tokio::test]
+    async fn test_recover_adopts_unlogged_shipment() {
+        let shop = shop();
+
+        // crashed after the shipment, before its step was logged
+        let saga_id = shop.log.start(111, 10, &address()).await.unwrap();
+        let reservation = shop.reservations.reserve(111, 10).await.unwrap();
+        let step = SagaStep::Reserved {
+            reservation_id: reservation.id,
+        };
+        shop.log.append(saga_id, &step).await.unwrap();
+        let shipment = shop
+            .shipments
+            .schedule_shipment(std::slice::from_ref(&reservation), &address())
+            .await
+            .unwrap();
+
+        let recovered = shop.purchases.recover().await.unwrap();
+        let purchase = recovered[0].1.as_ref().unwrap();
+        assert_eq!(purchase.shipment_id, shipment.id);
+        assert_eq!(
+            shop.shipments.shipment_of(reservation.id).await.unwrap(),
+            Some(shipment)
+        );
+        assert_eq!(shop.reservations.stock(111), Some(40));
+        assert!(shop.log.unfinished().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_reservation_is_compensated() {
+        let shop = shop();
+
+        // crashed before the confirmation and recovered after the TTL
+        let saga_id = shop.log.start(111, 10, &address()).await.unwrap();
+        let reservation = shop.reservations.reserve(111, 10).await.unwrap();
+        let step = SagaStep::Reserved {
+            reservation_id: reservation.id,
+        };
+        shop.log.append(saga_id, &step).await.unwrap();
+        let shipment = shop
+            .shipments
+            .schedule_shipment(std::slice::from_ref(&reservation), &address())
+            .await
+            .unwrap();
+        let step = SagaStep::Shipped {
+            shipment_id: shipment.id,
+        };
+        shop.log.append(saga_id, &step).await.unwrap();
+        tokio::time::advance(crate::shop::DEFAULT_RESERVATION_TTL).await;
+
+        let recovered = shop.purchases.recover().await.unwrap();
+        assert!(matches!(
+            recovered[0].1,
+            Err(PurchaseError::ReservationFailed(ReserveError::NotHeld {
+                status: ReservationStatus::Expired,
+                ..
+            }))
+        ));
+        assert_eq!(shop.reservations.stock(111), Some(50));
+        assert!(shop.log.unfinished().await.unwrap().is_empty());
+        // the shipment is canceled, so the reservation isn't on it anymore
+        shop.shipments
+            .schedule_shipment(&[reservation], &address())
+            .await
+            .unwrap();
+    }
+}
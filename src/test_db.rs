@@ -0,0 +1,49 @@
+//! Postgres in a container for the tests, connected through [`DbConfig`]
+
+use sqlx::PgPool;
+use testcontainers::{
+    core::{IntoContainerPort, WaitFor},
+    runners::AsyncRunner,
+    ContainerAsync, GenericImage, ImageExt,
+};
+
+use crate::{
+    app_config::{DbConfig, SslMode},
+    migrate::MIGRATOR,
+    secret::Secret,
+};
+
+/// A database with every migration applied. The container must outlive the pool.
+pub async fn start_postgres() -> (ContainerAsync<GenericImage>, PgPool) {
+    let (container, pool) = start_empty_postgres().await;
+    MIGRATOR.run(&pool).await.unwrap();
+    (container, pool)
+}
+
+/// A database without any migration
+pub async fn start_empty_postgres() -> (ContainerAsync<GenericImage>, PgPool) {
+    let container = GenericImage::new("postgres", "17-alpine")
+        .with_wait_for(WaitFor::message_on_stderr(
+            "database system is ready to accept connections",
+        ))
+        .with_exposed_port(5432.tcp())
+        .with_env_var("POSTGRES_PASSWORD", "1111")
+        .start()
+        .await
+        .expect("Postgres started");
+
+    let db = DbConfig {
+        host: container.get_host().await.unwrap().to_string(),
+        port: container.get_host_port_ipv4(5432).await.unwrap(),
+        login: "postgres".into(),
+        password: Some(Secret::new("1111".into())),
+        password_file: None,
+        password_env: None,
+        database: "postgres".into(),
+        pool_size: 10,
+        acquire_timeout_secs: 30,
+        ssl_mode: SslMode::Disable,
+    };
+    let pool = db.connect().await.unwrap();
+    (container, pool)
+}